@@ -0,0 +1,196 @@
+//! Experimental Schnorr adaptor signatures for point-time-locked contracts (PTLCs).
+//!
+//! An adaptor signature is a BIP-340 signature "encrypted" under a point `T = t·G`: it
+//! can be checked against `T` without knowing `t`, completed into a valid signature by
+//! whoever learns `t`, and once the completed signature is published anyone holding the
+//! adaptor can recover `t` from it. That last step replaces the preimage reveal of a
+//! hashlock in scriptless swaps.
+
+use crate::error::AdaptorError;
+use bitcoin::hashes::{Hash, HashEngine, sha256};
+use bitcoin::key::{Keypair, Parity};
+use bitcoin::secp256k1::{
+    All, Message, PublicKey, Scalar, Secp256k1, SecretKey, XOnlyPublicKey, schnorr,
+};
+use log::info;
+
+// Nonce candidates tried before giving up; each succeeds with probability ~1/2
+const MAX_NONCE_ATTEMPTS: u32 = 256;
+
+/// Signature over a message, encrypted under an encryption point.
+///
+/// `nonce` is the final nonce `R + T`, which always has an even y coordinate so the
+/// decrypted signature is a plain BIP-340 signature `(nonce.x, s + t)`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AdaptorSignature {
+    pub nonce: PublicKey,
+    pub s: SecretKey,
+}
+
+/// Creates an adaptor signature for `msg` under `encryption_point`.
+///
+/// The nonce is derived deterministically from the key, message and encryption point,
+/// like the no-aux-rand signatures of [`crate::tx_utils::sign_schnorr`].
+pub fn encrypt_signature(
+    secp: &Secp256k1<All>,
+    keypair: &Keypair,
+    msg: &Message,
+    encryption_point: &PublicKey,
+) -> Result<AdaptorSignature, AdaptorError> {
+    let (pubkey, parity) = keypair.x_only_public_key();
+    // BIP-340 signs with the key whose public point has an even y coordinate
+    let secret_key = match parity {
+        Parity::Even => keypair.secret_key(),
+        Parity::Odd => keypair.secret_key().negate(),
+    };
+
+    for attempt in 0..MAX_NONCE_ATTEMPTS {
+        let mut engine = sha256::Hash::engine();
+        engine.input(&secret_key.secret_bytes());
+        engine.input(msg.as_ref());
+        engine.input(&encryption_point.serialize());
+        engine.input(&attempt.to_be_bytes());
+        let Ok(nonce_key) =
+            SecretKey::from_slice(sha256::Hash::from_engine(engine).as_byte_array())
+        else {
+            continue;
+        };
+        let Ok(nonce) = PublicKey::from_secret_key(secp, &nonce_key).combine(encryption_point)
+        else {
+            continue;
+        };
+        if nonce.x_only_public_key().1 == Parity::Odd {
+            continue;
+        }
+
+        let challenge = challenge(&nonce.x_only_public_key().0, &pubkey, msg)?;
+        let s = secret_key
+            .mul_tweak(&challenge)
+            .and_then(|ex| nonce_key.add_tweak(&Scalar::from(ex)))
+            .map_err(|e| AdaptorError::Arithmetic(e.to_string()))?;
+        info!(
+            "Created adaptor signature after {} nonce attempts",
+            attempt + 1
+        );
+        return Ok(AdaptorSignature { nonce, s });
+    }
+    Err(AdaptorError::NonceGeneration)
+}
+
+/// Checks that `adaptor` decrypts, with the discrete log of `encryption_point`, to a
+/// valid signature of `msg` by `pubkey`: `s·G == nonce - T + e·P`.
+pub fn verify_adaptor(
+    secp: &Secp256k1<All>,
+    adaptor: &AdaptorSignature,
+    pubkey: &XOnlyPublicKey,
+    msg: &Message,
+    encryption_point: &PublicKey,
+) -> bool {
+    if adaptor.nonce.x_only_public_key().1 == Parity::Odd {
+        return false;
+    }
+    let Ok(challenge) = challenge(&adaptor.nonce.x_only_public_key().0, pubkey, msg) else {
+        return false;
+    };
+    let expected = pubkey
+        .public_key(Parity::Even)
+        .mul_tweak(secp, &challenge)
+        .and_then(|ep| ep.combine(&adaptor.nonce))
+        .and_then(|sum| sum.combine(&encryption_point.negate(secp)));
+    expected.is_ok_and(|expected| expected == PublicKey::from_secret_key(secp, &adaptor.s))
+}
+
+/// Completes `adaptor` into a BIP-340 signature using the secret `t` of its
+/// encryption point.
+pub fn decrypt_signature(
+    adaptor: &AdaptorSignature,
+    secret: &SecretKey,
+) -> Result<schnorr::Signature, AdaptorError> {
+    let s = adaptor
+        .s
+        .add_tweak(&Scalar::from(*secret))
+        .map_err(|e| AdaptorError::Arithmetic(e.to_string()))?;
+    let mut bytes = [0u8; 64];
+    bytes[..32].copy_from_slice(&adaptor.nonce.x_only_public_key().0.serialize());
+    bytes[32..].copy_from_slice(&s.secret_bytes());
+    schnorr::Signature::from_slice(&bytes).map_err(|e| AdaptorError::Arithmetic(e.to_string()))
+}
+
+/// Recovers the secret `t` from an adaptor and the signature decrypted from it.
+pub fn recover_secret(
+    adaptor: &AdaptorSignature,
+    final_sig: &schnorr::Signature,
+) -> Result<SecretKey, AdaptorError> {
+    let bytes = final_sig.as_ref();
+    if bytes[..32] != adaptor.nonce.x_only_public_key().0.serialize() {
+        return Err(AdaptorError::NonceMismatch);
+    }
+    SecretKey::from_slice(&bytes[32..])
+        .and_then(|s| s.add_tweak(&Scalar::from(adaptor.s.negate())))
+        .map_err(|e| AdaptorError::Arithmetic(e.to_string()))
+}
+
+/// BIP-340 challenge `e = H_tag("BIP0340/challenge", R.x || P.x || m)`.
+fn challenge(
+    nonce: &XOnlyPublicKey,
+    pubkey: &XOnlyPublicKey,
+    msg: &Message,
+) -> Result<Scalar, AdaptorError> {
+    let tag = sha256::Hash::hash(b"BIP0340/challenge");
+    let mut engine = sha256::Hash::engine();
+    engine.input(tag.as_byte_array());
+    engine.input(tag.as_byte_array());
+    engine.input(&nonce.serialize());
+    engine.input(&pubkey.serialize());
+    engine.input(msg.as_ref());
+    Scalar::from_be_bytes(sha256::Hash::from_engine(engine).to_byte_array())
+        .map_err(|e| AdaptorError::Arithmetic(e.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::deterministic_keypair;
+    use crate::tx_utils::new_secp_context;
+
+    #[test]
+    fn test_adaptor_round_trip() {
+        let secp = new_secp_context();
+        let msg = Message::from_digest([7u8; 32]);
+        let (secret, _) = deterministic_keypair(2);
+        let encryption_point = PublicKey::from_secret_key(&secp, &secret);
+
+        // Cover signing keys of both parities
+        let mut parities = Vec::new();
+        for seed in [1, 3, 4, 5] {
+            let (signing_key, pubkey) = deterministic_keypair(seed);
+            let keypair = Keypair::from_secret_key(&secp, &signing_key);
+            parities.push(keypair.x_only_public_key().1);
+
+            let adaptor = encrypt_signature(&secp, &keypair, &msg, &encryption_point).unwrap();
+            assert!(verify_adaptor(
+                &secp,
+                &adaptor,
+                &pubkey,
+                &msg,
+                &encryption_point
+            ));
+            let other_point = PublicKey::from_secret_key(&secp, &deterministic_keypair(9).0);
+            assert!(!verify_adaptor(
+                &secp,
+                &adaptor,
+                &pubkey,
+                &msg,
+                &other_point
+            ));
+
+            let signature = decrypt_signature(&adaptor, &secret).unwrap();
+            assert!(secp.verify_schnorr(&signature, &msg, &pubkey).is_ok());
+            assert_eq!(recover_secret(&adaptor, &signature).unwrap(), secret);
+
+            let wrong = decrypt_signature(&adaptor, &deterministic_keypair(9).0).unwrap();
+            assert!(secp.verify_schnorr(&wrong, &msg, &pubkey).is_err());
+        }
+        assert!(parities.contains(&Parity::Even) && parities.contains(&Parity::Odd));
+    }
+}
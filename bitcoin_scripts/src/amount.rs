@@ -0,0 +1,99 @@
+use crate::error::AmountError;
+
+/// Satoshis per bitcoin.
+const SATS_PER_BTC: u64 = 100_000_000;
+
+/// Decimal places of a BTC amount.
+const BTC_DECIMALS: usize = 8;
+
+/// Total supply cap in satoshis (21 million BTC).
+const MAX_MONEY_SATS: u64 = 21_000_000 * SATS_PER_BTC;
+
+/// Formats satoshis as a BTC string with all eight decimals, e.g. `1` → `"0.00000001"`.
+pub fn sats_to_btc_string(sats: u64) -> String {
+    format!(
+        "{}.{:0width$}",
+        sats / SATS_PER_BTC,
+        sats % SATS_PER_BTC,
+        width = BTC_DECIMALS
+    )
+}
+
+/// Parses a decimal BTC string such as `"0.5"` or `"21000000"` into satoshis.
+///
+/// Parsing is done on the digits, so no float rounding is involved. More than eight
+/// decimals, signs, exponents and amounts above 21 million BTC are rejected.
+pub fn btc_string_to_sats(s: &str) -> Result<u64, AmountError> {
+    let s = s.trim();
+    let (whole, fraction) = s.split_once('.').unwrap_or((s, ""));
+    let is_digits = |part: &str| part.bytes().all(|b| b.is_ascii_digit());
+    if whole.is_empty() || !is_digits(whole) || !is_digits(fraction) || s.ends_with('.') {
+        return Err(AmountError::InvalidFormat(s.to_string()));
+    }
+    if fraction.len() > BTC_DECIMALS {
+        return Err(AmountError::TooPrecise(s.to_string()));
+    }
+
+    let whole: u64 = whole
+        .parse()
+        .map_err(|_| AmountError::OutOfRange(s.to_string()))?;
+    let fraction: u64 = format!("{:0<width$}", fraction, width = BTC_DECIMALS)
+        .parse()
+        .map_err(|_| AmountError::InvalidFormat(s.to_string()))?;
+    whole
+        .checked_mul(SATS_PER_BTC)
+        .and_then(|sats| sats.checked_add(fraction))
+        .filter(|sats| *sats <= MAX_MONEY_SATS)
+        .ok_or_else(|| AmountError::OutOfRange(s.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sat_btc_string_conversions() {
+        assert_eq!(sats_to_btc_string(0), "0.00000000");
+        assert_eq!(sats_to_btc_string(1), "0.00000001");
+        assert_eq!(sats_to_btc_string(10_000), "0.00010000");
+        assert_eq!(sats_to_btc_string(MAX_MONEY_SATS), "21000000.00000000");
+
+        assert_eq!(btc_string_to_sats("0.00000001").unwrap(), 1);
+        assert_eq!(btc_string_to_sats("0.1").unwrap(), 10_000_000);
+        assert_eq!(btc_string_to_sats("1").unwrap(), SATS_PER_BTC);
+        assert_eq!(btc_string_to_sats("21000000").unwrap(), MAX_MONEY_SATS);
+        assert_eq!(
+            btc_string_to_sats("21000000.00000000").unwrap(),
+            MAX_MONEY_SATS
+        );
+        // 0.1 + 0.2 style inputs stay exact
+        assert_eq!(btc_string_to_sats("0.30000000").unwrap(), 30_000_000);
+
+        for sats in [1, 12_345_678, 99_999_999, MAX_MONEY_SATS] {
+            assert_eq!(btc_string_to_sats(&sats_to_btc_string(sats)).unwrap(), sats);
+        }
+
+        assert!(matches!(
+            btc_string_to_sats("0.000000001"),
+            Err(AmountError::TooPrecise(_))
+        ));
+        assert!(matches!(
+            btc_string_to_sats("21000000.00000001"),
+            Err(AmountError::OutOfRange(_))
+        ));
+        assert!(matches!(
+            btc_string_to_sats("99999999999999999999"),
+            Err(AmountError::OutOfRange(_))
+        ));
+        for invalid in ["", ".5", "1.", "-1", "1e3", "1.2.3", "abc"] {
+            assert!(
+                matches!(
+                    btc_string_to_sats(invalid),
+                    Err(AmountError::InvalidFormat(_))
+                ),
+                "{:?} should be rejected",
+                invalid
+            );
+        }
+    }
+}
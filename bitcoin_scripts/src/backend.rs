@@ -0,0 +1,526 @@
+use crate::error::UtilsError;
+use crate::utils::{
+    FeeTier, MempoolBlock, RecommendedFeeRate, Utxo, broadcast_trx, default_esplora_url,
+    fetch_fee_estimates, fetch_mempool_blocks, fetch_recommended_fee_rate, fetch_tip_block_height,
+    fetch_transaction, fetch_utxos_for_address, is_outpoint_spent, submit_package,
+};
+use bitcoin::{Address, Network, Transaction, Txid};
+use log::{error, info};
+use std::collections::BTreeMap;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+/// Chain data source used by the async swap helpers.
+#[allow(async_fn_in_trait)]
+pub trait Backend {
+    async fn fetch_tip_block_height(&self) -> Result<u32, UtilsError>;
+
+    async fn fetch_utxos_for_address(&self, address: &Address) -> Result<Vec<Utxo>, UtilsError>;
+
+    async fn broadcast_trx(&self, trx_raw_hex: &str) -> Result<String, UtilsError>;
+
+    /// Broadcasts `txs_hex` in order, parents first, returning their txids. Backends
+    /// that can relay packages atomically override this sequential default.
+    async fn broadcast_package(&self, txs_hex: &[&str]) -> Result<Vec<String>, UtilsError> {
+        let mut txids = Vec::new();
+        for tx_hex in txs_hex {
+            txids.push(self.broadcast_trx(tx_hex).await?);
+        }
+        Ok(txids)
+    }
+
+    /// Returns `None` if the backend does not know the transaction.
+    async fn fetch_transaction(&self, txid: &Txid) -> Result<Option<Transaction>, UtilsError>;
+
+    /// Returns the txid spending output `vout` of `txid`, or `None` while it is unspent.
+    async fn is_outpoint_spent(&self, txid: &Txid, vout: u32) -> Result<Option<Txid>, UtilsError>;
+
+    async fn fetch_recommended_fee_rate(&self) -> Result<RecommendedFeeRate, UtilsError>;
+
+    /// Fee rates in sat/vB keyed by confirmation target in blocks.
+    async fn fetch_fee_estimates(&self) -> Result<BTreeMap<u16, f64>, UtilsError>;
+
+    /// Blocks projected from the current mempool, next block first.
+    async fn fetch_mempool_blocks(&self) -> Result<Vec<MempoolBlock>, UtilsError>;
+}
+
+/// Source of fee rates for the `*_auto_fee` builders.
+///
+/// Every [`Backend`] is a source that queries its fee API. [`StaticFeeRate`] and
+/// [`CallbackFeeRate`] skip the fee API, e.g. for reproducible CI runs or a fee policy
+/// set by the caller.
+#[allow(async_fn_in_trait)]
+pub trait FeeSource {
+    /// Rate in sat/vB for `tier`, or `None` if the source has none.
+    async fn fee_rate(&self, tier: FeeTier) -> Result<Option<u64>, UtilsError>;
+}
+
+impl<B: Backend> FeeSource for B {
+    async fn fee_rate(&self, tier: FeeTier) -> Result<Option<u64>, UtilsError> {
+        Ok(self.fetch_recommended_fee_rate().await?.rate_for(tier))
+    }
+}
+
+/// The same sat/vB rate for every tier.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StaticFeeRate(pub u64);
+
+impl FeeSource for StaticFeeRate {
+    async fn fee_rate(&self, _tier: FeeTier) -> Result<Option<u64>, UtilsError> {
+        Ok(Some(self.0))
+    }
+}
+
+/// Rates supplied by a caller-provided function of the tier.
+pub struct CallbackFeeRate<F: Fn(FeeTier) -> Option<u64>>(pub F);
+
+impl<F: Fn(FeeTier) -> Option<u64>> FeeSource for CallbackFeeRate<F> {
+    async fn fee_rate(&self, tier: FeeTier) -> Result<Option<u64>, UtilsError> {
+        Ok((self.0)(tier))
+    }
+}
+
+/// Backend talking to an esplora-compatible REST API.
+#[derive(Debug, Clone)]
+pub struct EsploraBackend {
+    pub rpc_url: String,
+}
+
+impl EsploraBackend {
+    pub fn new(rpc_url: &str) -> Self {
+        EsploraBackend {
+            rpc_url: rpc_url.to_string(),
+        }
+    }
+
+    /// Backend using [`default_esplora_url`] for `network`.
+    pub fn for_network(network: Network) -> Self {
+        Self::new(default_esplora_url(network))
+    }
+}
+
+impl Backend for EsploraBackend {
+    async fn fetch_tip_block_height(&self) -> Result<u32, UtilsError> {
+        fetch_tip_block_height(&self.rpc_url).await
+    }
+
+    async fn fetch_utxos_for_address(&self, address: &Address) -> Result<Vec<Utxo>, UtilsError> {
+        fetch_utxos_for_address(&self.rpc_url, address).await
+    }
+
+    async fn broadcast_trx(&self, trx_raw_hex: &str) -> Result<String, UtilsError> {
+        broadcast_trx(&self.rpc_url, trx_raw_hex).await
+    }
+
+    async fn fetch_transaction(&self, txid: &Txid) -> Result<Option<Transaction>, UtilsError> {
+        fetch_transaction(&self.rpc_url, txid).await
+    }
+
+    async fn is_outpoint_spent(&self, txid: &Txid, vout: u32) -> Result<Option<Txid>, UtilsError> {
+        is_outpoint_spent(&self.rpc_url, txid, vout).await
+    }
+
+    async fn fetch_recommended_fee_rate(&self) -> Result<RecommendedFeeRate, UtilsError> {
+        fetch_recommended_fee_rate(&self.rpc_url).await
+    }
+
+    async fn fetch_fee_estimates(&self) -> Result<BTreeMap<u16, f64>, UtilsError> {
+        fetch_fee_estimates(&self.rpc_url).await
+    }
+
+    async fn fetch_mempool_blocks(&self) -> Result<Vec<MempoolBlock>, UtilsError> {
+        fetch_mempool_blocks(&self.rpc_url).await
+    }
+
+    /// Uses `/txs/package` where available, falling back to ordered submission.
+    async fn broadcast_package(&self, txs_hex: &[&str]) -> Result<Vec<String>, UtilsError> {
+        if let Some(txids) = submit_package(&self.rpc_url, txs_hex).await? {
+            return Ok(txids);
+        }
+        let mut txids = Vec::new();
+        for tx_hex in txs_hex {
+            txids.push(broadcast_trx(&self.rpc_url, tx_hex).await?);
+        }
+        Ok(txids)
+    }
+}
+
+/// Broadcasts a parent and a CPFP child paying for it together, so the child is not
+/// rejected for spending an output the node has not seen. Returns both txids.
+pub async fn broadcast_package<B: Backend>(
+    backend: &B,
+    parent_hex: &str,
+    child_hex: &str,
+) -> Result<(String, String), UtilsError> {
+    let txids = backend.broadcast_package(&[parent_hex, child_hex]).await?;
+    match <[String; 2]>::try_from(txids) {
+        Ok([parent_txid, child_txid]) => Ok((parent_txid, child_txid)),
+        Err(txids) => Err(UtilsError::ParseError(format!(
+            "expected 2 txids for the package, got {}",
+            txids.len()
+        ))),
+    }
+}
+
+/// How long [`broadcast_and_verify`] waits before checking that the server kept the
+/// transaction.
+const BROADCAST_VERIFY_DELAY: Duration = Duration::from_secs(2);
+
+/// Broadcasts `trx_raw_hex`, then confirms the server actually knows the returned txid,
+/// so a server that acknowledges a transaction and then drops it is caught. The returned
+/// txid must also match the transaction's own.
+pub async fn broadcast_and_verify<B: Backend>(
+    backend: &B,
+    trx_raw_hex: &str,
+) -> Result<Txid, UtilsError> {
+    broadcast_and_verify_after(backend, trx_raw_hex, BROADCAST_VERIFY_DELAY).await
+}
+
+/// [`broadcast_and_verify`] with a custom wait before the follow-up lookup.
+pub async fn broadcast_and_verify_after<B: Backend>(
+    backend: &B,
+    trx_raw_hex: &str,
+    delay: Duration,
+) -> Result<Txid, UtilsError> {
+    let tx: Transaction =
+        bitcoin::consensus::encode::deserialize_hex(trx_raw_hex).map_err(|e| {
+            error!("Failed to decode transaction for broadcast: {}", e);
+            UtilsError::ParseError(e.to_string())
+        })?;
+    let expected = tx.compute_txid();
+    let txid = backend.broadcast_trx(trx_raw_hex).await?;
+    let txid = Txid::from_str(&txid).map_err(|e| {
+        error!("Broadcast returned an invalid txid '{}': {}", txid, e);
+        UtilsError::ParseError(e.to_string())
+    })?;
+    if txid != expected {
+        error!(
+            "Broadcast returned txid {} for transaction {}",
+            txid, expected
+        );
+        return Err(UtilsError::BroadcastTxidMismatch {
+            expected: expected.to_string(),
+            actual: txid.to_string(),
+        });
+    }
+    tokio::time::sleep(delay).await;
+    match backend.fetch_transaction(&txid).await? {
+        Some(_) => {
+            info!("Verified broadcast of {}", txid);
+            Ok(txid)
+        }
+        None => {
+            error!(
+                "Broadcast of {} was acknowledged but the server does not know it",
+                txid
+            );
+            Err(UtilsError::BroadcastNotFound(txid.to_string()))
+        }
+    }
+}
+
+/// Caches the chain tip height for `ttl` so bursts of spends share one backend call.
+///
+/// A refresh always replaces the cached height, so a re-org that lowers the tip is
+/// picked up once the TTL expires. Call [`TipHeightCache::invalidate`] to refetch
+/// immediately, e.g. after seeing a block disconnect.
+#[derive(Debug, Clone)]
+pub struct TipHeightCache {
+    value: u32,
+    fetched_at: Option<Instant>,
+    ttl: Duration,
+}
+
+impl TipHeightCache {
+    pub fn new(ttl: Duration) -> Self {
+        TipHeightCache {
+            value: 0,
+            fetched_at: None,
+            ttl,
+        }
+    }
+
+    /// Returns the cached tip height, fetching it from `backend` if the cache is empty
+    /// or older than the TTL.
+    pub async fn get<B: Backend>(&mut self, backend: &B) -> Result<u32, UtilsError> {
+        if let Some(fetched_at) = self.fetched_at
+            && fetched_at.elapsed() < self.ttl
+        {
+            return Ok(self.value);
+        }
+        let value = backend.fetch_tip_block_height().await?;
+        self.value = value;
+        self.fetched_at = Some(Instant::now());
+        Ok(value)
+    }
+
+    /// Drops the cached height so the next [`TipHeightCache::get`] hits the backend.
+    pub fn invalidate(&mut self) {
+        self.fetched_at = None;
+    }
+}
+
+#[cfg(test)]
+pub mod mock {
+    use super::*;
+    use bitcoin::OutPoint;
+    use std::collections::HashMap;
+    use std::sync::Mutex;
+
+    /// In-memory backend for tests.
+    #[derive(Debug, Default)]
+    pub struct MockBackend {
+        pub tip_height: Mutex<u32>,
+        pub utxos: Mutex<Vec<Utxo>>,
+        pub broadcasts: Mutex<Vec<String>>,
+        pub transactions: Mutex<HashMap<Txid, Transaction>>,
+        pub fee_rates: Mutex<RecommendedFeeRate>,
+        pub tip_fetches: Mutex<usize>,
+        /// Blocks added to the tip after every height fetch, to simulate a growing chain.
+        pub tip_advance: Mutex<u32>,
+        pub fee_estimates: Mutex<BTreeMap<u16, f64>>,
+        /// Per-address UTXOs, taking precedence over `utxos` for the addresses present.
+        pub address_utxos: Mutex<HashMap<String, Vec<Utxo>>>,
+        /// Spent outpoints and the txid spending each.
+        pub spent_outpoints: Mutex<HashMap<OutPoint, Txid>>,
+        pub mempool_blocks: Mutex<Vec<MempoolBlock>>,
+    }
+
+    impl MockBackend {
+        pub fn with_tip(tip_height: u32) -> Self {
+            MockBackend {
+                tip_height: Mutex::new(tip_height),
+                ..Default::default()
+            }
+        }
+
+        pub fn set_tip(&self, tip_height: u32) {
+            *self.tip_height.lock().unwrap() = tip_height;
+        }
+
+        pub fn set_utxos(&self, utxos: Vec<Utxo>) {
+            *self.utxos.lock().unwrap() = utxos;
+        }
+
+        pub fn set_address_utxos(&self, address: &str, utxos: Vec<Utxo>) {
+            self.address_utxos
+                .lock()
+                .unwrap()
+                .insert(address.to_string(), utxos);
+        }
+
+        pub fn mark_spent(&self, outpoint: OutPoint, spending_txid: Txid) {
+            self.spent_outpoints
+                .lock()
+                .unwrap()
+                .insert(outpoint, spending_txid);
+        }
+
+        pub fn add_transaction(&self, tx: Transaction) {
+            self.transactions
+                .lock()
+                .unwrap()
+                .insert(tx.compute_txid(), tx);
+        }
+
+        pub fn set_fee_rates(&self, fee_rates: RecommendedFeeRate) {
+            *self.fee_rates.lock().unwrap() = fee_rates;
+        }
+    }
+
+    impl Backend for MockBackend {
+        async fn fetch_tip_block_height(&self) -> Result<u32, UtilsError> {
+            *self.tip_fetches.lock().unwrap() += 1;
+            let mut tip_height = self.tip_height.lock().unwrap();
+            let current = *tip_height;
+            *tip_height += *self.tip_advance.lock().unwrap();
+            Ok(current)
+        }
+
+        async fn fetch_utxos_for_address(
+            &self,
+            address: &Address,
+        ) -> Result<Vec<Utxo>, UtilsError> {
+            if let Some(utxos) = self.address_utxos.lock().unwrap().get(&address.to_string()) {
+                return Ok(utxos.clone());
+            }
+            Ok(self.utxos.lock().unwrap().clone())
+        }
+
+        async fn broadcast_trx(&self, trx_raw_hex: &str) -> Result<String, UtilsError> {
+            let tx: bitcoin::Transaction = bitcoin::consensus::encode::deserialize_hex(trx_raw_hex)
+                .map_err(|e| UtilsError::ParseError(e.to_string()))?;
+            self.broadcasts
+                .lock()
+                .unwrap()
+                .push(trx_raw_hex.to_string());
+            Ok(tx.compute_txid().to_string())
+        }
+
+        async fn fetch_transaction(&self, txid: &Txid) -> Result<Option<Transaction>, UtilsError> {
+            Ok(self.transactions.lock().unwrap().get(txid).cloned())
+        }
+
+        async fn is_outpoint_spent(
+            &self,
+            txid: &Txid,
+            vout: u32,
+        ) -> Result<Option<Txid>, UtilsError> {
+            let outpoint = OutPoint::new(*txid, vout);
+            Ok(self.spent_outpoints.lock().unwrap().get(&outpoint).copied())
+        }
+
+        async fn fetch_recommended_fee_rate(&self) -> Result<RecommendedFeeRate, UtilsError> {
+            Ok(self.fee_rates.lock().unwrap().clone())
+        }
+
+        async fn fetch_fee_estimates(&self) -> Result<BTreeMap<u16, f64>, UtilsError> {
+            Ok(self.fee_estimates.lock().unwrap().clone())
+        }
+
+        async fn fetch_mempool_blocks(&self) -> Result<Vec<MempoolBlock>, UtilsError> {
+            Ok(self.mempool_blocks.lock().unwrap().clone())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::mock::MockBackend;
+    use super::*;
+    use crate::utils::tests::{MockResponse, spawn_mock_server};
+
+    #[tokio::test]
+    async fn test_tip_height_cache_serves_within_ttl() {
+        let backend = MockBackend::with_tip(800_000);
+        let mut cache = TipHeightCache::new(Duration::from_secs(60));
+
+        assert_eq!(cache.get(&backend).await.unwrap(), 800_000);
+        backend.set_tip(800_001);
+        assert_eq!(cache.get(&backend).await.unwrap(), 800_000);
+        assert_eq!(*backend.tip_fetches.lock().unwrap(), 1);
+
+        // A re-org lowering the tip is seen after invalidation
+        backend.set_tip(799_999);
+        cache.invalidate();
+        assert_eq!(cache.get(&backend).await.unwrap(), 799_999);
+        assert_eq!(*backend.tip_fetches.lock().unwrap(), 2);
+
+        let mut expired = TipHeightCache::new(Duration::ZERO);
+        expired.get(&backend).await.unwrap();
+        expired.get(&backend).await.unwrap();
+        assert_eq!(*backend.tip_fetches.lock().unwrap(), 4);
+    }
+
+    const BROADCAST_HEX: &str = "0200000000010187570c9750db9664197ca865bbf0f26f2f6378be46273a7f53578f2fc45f8a9c0000000000fdffffff011d02000000000000160014fe73249e6fa4b5a7a7d5068a175d8441e7a53cc204404eacbf793abd7b64584d88f3ae2a29fdaf2a665526ebfa52b812f6492598c10c4289036e12bdca361adcd2c0b9d179a39b4cfa7de9c75464e3ed43698feae86c20e235db8c009db64dcd2b6ab8295afc024f46c23c24e1dde0e984fd08cdb47a9145a8201572a86fb4b1f15623da10e34034fd151090d37e6f0f3ef4f69926f7f3388b788820f1946d446157bc98699db7271d2fe9495ea4bcf25eb81b645c89803e18af9a22ac41c150929b74c1a04954b78b4b6035e97a5e078a5a0f28ec96d547bfee9ace803ac0abd07cb2de3b9cf682858acc9bd1a7ba39cfc7019a115c5713a445b7e2df1bed00000000";
+
+    #[tokio::test]
+    async fn test_broadcast_and_verify_detects_missing_tx() {
+        let tx: Transaction = bitcoin::consensus::encode::deserialize_hex(BROADCAST_HEX).unwrap();
+        let txid = tx.compute_txid().to_string();
+        let (base_url, requests) = spawn_mock_server(vec![
+            MockResponse::new(200, &txid),
+            MockResponse::new(404, "Transaction not found"),
+        ]);
+        let backend = EsploraBackend::new(&base_url);
+
+        let result = broadcast_and_verify_after(&backend, BROADCAST_HEX, Duration::ZERO).await;
+        match result {
+            Err(UtilsError::BroadcastNotFound(missing)) => assert_eq!(missing, txid),
+            other => panic!("Expected BroadcastNotFound, got {:?}", other),
+        }
+        let requests = requests.lock().unwrap();
+        assert!(requests[1].starts_with(&format!("GET /tx/{}/hex ", txid)));
+    }
+
+    #[tokio::test]
+    async fn test_broadcast_and_verify_rejects_wrong_txid() {
+        let tx: Transaction = bitcoin::consensus::encode::deserialize_hex(BROADCAST_HEX).unwrap();
+        let wrong_txid = "11".repeat(32);
+        let (base_url, requests) = spawn_mock_server(vec![MockResponse::new(200, &wrong_txid)]);
+        let backend = EsploraBackend::new(&base_url);
+
+        let err = broadcast_and_verify_after(&backend, BROADCAST_HEX, Duration::ZERO)
+            .await
+            .unwrap_err();
+        match &err {
+            UtilsError::BroadcastTxidMismatch { expected, actual } => {
+                assert_eq!(*expected, tx.compute_txid().to_string());
+                assert_eq!(*actual, wrong_txid);
+            }
+            other => panic!("Expected BroadcastTxidMismatch, got {:?}", other),
+        }
+        assert_eq!(err.code(), "broadcast_txid_mismatch");
+        assert_eq!(requests.lock().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_broadcast_and_verify_accepts_known_tx() {
+        let tx: Transaction = bitcoin::consensus::encode::deserialize_hex(BROADCAST_HEX).unwrap();
+        let backend = MockBackend::default();
+        backend.add_transaction(tx.clone());
+
+        let txid = broadcast_and_verify_after(&backend, BROADCAST_HEX, Duration::ZERO)
+            .await
+            .unwrap();
+        assert_eq!(txid, tx.compute_txid());
+    }
+
+    #[tokio::test]
+    async fn test_broadcast_package() {
+        let parent: Transaction =
+            bitcoin::consensus::encode::deserialize_hex(BROADCAST_HEX).unwrap();
+        let mut child = parent.clone();
+        child.input[0].previous_output = bitcoin::OutPoint::new(parent.compute_txid(), 0);
+        child.input[0].witness = bitcoin::Witness::new();
+        let child_hex = bitcoin::consensus::encode::serialize_hex(&child);
+        let parent_txid = parent.compute_txid().to_string();
+        let child_txid = child.compute_txid().to_string();
+
+        let (base_url, requests) = spawn_mock_server(vec![
+            MockResponse::new(
+                200,
+                r#"{"package_msg":"success","tx-results":{},"replaced-transactions":[]}"#,
+            ),
+            // A server without the package endpoint gets the transactions in order
+            MockResponse::new(404, "Not Found"),
+            MockResponse::new(200, &parent_txid),
+            MockResponse::new(200, &child_txid),
+            MockResponse::new(
+                200,
+                r#"{"package_msg":"transaction failed","tx-results":{"ab":{"txid":"cd","error":"min relay fee not met"}}}"#,
+            ),
+        ]);
+        let backend = EsploraBackend::new(&base_url);
+
+        let txids = broadcast_package(&backend, BROADCAST_HEX, &child_hex)
+            .await
+            .unwrap();
+        assert_eq!(txids, (parent_txid.clone(), child_txid.clone()));
+        let txids = broadcast_package(&backend, BROADCAST_HEX, &child_hex)
+            .await
+            .unwrap();
+        assert_eq!(txids, (parent_txid.clone(), child_txid.clone()));
+        {
+            let requests = requests.lock().unwrap();
+            assert!(requests[0].starts_with("POST /txs/package "));
+            assert!(requests[1].starts_with("POST /txs/package "));
+            assert!(requests[2].starts_with("POST /tx "));
+            assert!(requests[3].starts_with("POST /tx "));
+        }
+        assert!(matches!(
+            broadcast_package(&backend, BROADCAST_HEX, &child_hex).await,
+            Err(UtilsError::BroadcastError { message, .. }) if message.contains("min relay fee")
+        ));
+
+        // Backends without package relay submit sequentially, parent first
+        let mock = MockBackend::default();
+        broadcast_package(&mock, BROADCAST_HEX, &child_hex)
+            .await
+            .unwrap();
+        assert_eq!(
+            *mock.broadcasts.lock().unwrap(),
+            vec![BROADCAST_HEX.to_string(), child_hex]
+        );
+    }
+}
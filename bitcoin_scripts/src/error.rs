@@ -0,0 +1,389 @@
+use crate::p2tr::{MAX_OP_RETURN_SIZE, SpendBranch};
+use crate::swap::{CsvUnit, HashKind};
+use bitcoin::{
+    Amount, address::ParseError as AddressParseError, bip32, key::FromWifError,
+    locktime::absolute::ConversionError, script::PushBytesError, secp256k1,
+    taproot::TaprootBuilderError,
+};
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+use thiserror::Error;
+
+/// Stable, serializable error representation for API responses.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ApiError {
+    pub code: String,
+    pub message: String,
+}
+
+impl ApiError {
+    /// `code` with the message of `err` followed by those of its sources, which the
+    /// `#[error]` messages leave out.
+    fn new(code: &str, err: &dyn std::error::Error) -> Self {
+        let mut message = err.to_string();
+        let mut source = err.source();
+        while let Some(cause) = source {
+            message.push_str(": ");
+            message.push_str(&cause.to_string());
+            source = cause.source();
+        }
+        ApiError {
+            code: code.to_string(),
+            message,
+        }
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum UtilsError {
+    #[error("HTTP request failed")]
+    HttpRequestError(#[source] reqwest::Error),
+    #[error("Failed to parse response: {0}")]
+    ParseError(String),
+    #[error("Broadcast failed with status {status}: {message}")]
+    BroadcastError {
+        status: reqwest::StatusCode,
+        message: String,
+    },
+    #[error("Rate limited, retry after {retry_after:?}")]
+    RateLimited { retry_after: Duration },
+    #[error("Broadcast transaction {0} is not known to the server")]
+    BroadcastNotFound(String),
+    #[error("Broadcast returned txid {actual}, but the transaction's txid is {expected}")]
+    BroadcastTxidMismatch { expected: String, actual: String },
+}
+
+impl UtilsError {
+    /// Stable machine-readable code for this error.
+    pub fn code(&self) -> &'static str {
+        match self {
+            UtilsError::HttpRequestError(_) => "http_request_error",
+            UtilsError::ParseError(_) => "parse_error",
+            UtilsError::BroadcastError { .. } => "broadcast_error",
+            UtilsError::RateLimited { .. } => "rate_limited",
+            UtilsError::BroadcastNotFound(_) => "broadcast_not_found",
+            UtilsError::BroadcastTxidMismatch { .. } => "broadcast_txid_mismatch",
+        }
+    }
+
+    pub fn to_api_error(&self) -> ApiError {
+        ApiError::new(self.code(), self)
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum TxUtilsError {
+    #[error("Invalid private key")]
+    InvalidPrivateKey(#[source] secp256k1::Error),
+    #[error("Invalid extended private key")]
+    InvalidExtendedPrivateKey(#[source] bip32::Error),
+    #[error("Failed to compute Taproot sighash: {0}")]
+    SighashComputationError(String),
+    #[error("Invalid derivation path")]
+    InvalidDerivationPath(#[source] bip32::Error),
+    #[error("Invalid WIF private key")]
+    InvalidWif(#[source] FromWifError),
+    #[error("Script verification failed for input {index}: {reason}")]
+    ScriptVerificationFailed { index: usize, reason: String },
+}
+
+#[derive(Error, Debug)]
+pub enum TaprootError {
+    #[error("Invalid HTLC type for P2TR address: {0}")]
+    InvalidHtlcType(String),
+    #[error("Timelock must be positive")]
+    InvalidTimelock,
+    #[error("Invalid NUMS point")]
+    InvalidNumsPoint(#[source] secp256k1::Error),
+    #[error("Failed to build Taproot spend info")]
+    TaprootBuildError,
+    #[error("Invalid payment hash")]
+    InvalidPaymentHash(#[source] hex::FromHexError),
+    #[error("Failed to create PushBytesBuf")]
+    PushBytesBufError(#[source] PushBytesError),
+    #[error("Invalid responder pubkey")]
+    InvalidResponderPubkey(#[source] secp256k1::Error),
+    #[error("Invalid initiator pubkey")]
+    InvalidInitiatorPubkey(#[source] secp256k1::Error),
+    #[error("Invalid redeemer pubkey")]
+    InvalidRedeemerPubkey(#[source] secp256k1::Error),
+    #[error("Failed to get control block")]
+    ControlBlockError,
+    #[error("Invalid preimage hex")]
+    InvalidPreimage(#[source] hex::FromHexError),
+    #[error("Failed to compute sighash for input {index}")]
+    SighashError {
+        index: usize,
+        #[source]
+        source: TxUtilsError,
+    },
+    #[error("Invalid Txid: {0}")]
+    InvalidTxid(String),
+    #[error("Invalid private key")]
+    InvalidPrivateKey(#[source] TxUtilsError),
+    #[error("Private key does not match the HTLC: {0}")]
+    PrivateKeyMismatch(String),
+    #[error("Taproot builder error: {0}")]
+    TaprootBuilderError(#[from] TaprootBuilderError),
+    #[error("HTLC has no absolute timelock")]
+    MissingAbsoluteTimelock,
+    #[error("Invalid absolute timelock")]
+    InvalidAbsoluteTimelock(#[source] ConversionError),
+    #[error("No refund path is mature yet: {0}")]
+    TimelockNotMature(String),
+    #[error("Backend request failed")]
+    BackendError(#[source] UtilsError),
+    #[error("No UTXOs provided for HTLC address: {0}")]
+    NoUtxos(String),
+    #[error("Initiator pubkey must differ from the responder and redeemer pubkeys")]
+    DuplicatePubkeys,
+    #[error("Invalid address")]
+    InvalidAddress(#[source] AddressParseError),
+    #[error("Address network mismatch")]
+    NetworkMismatch(#[source] AddressParseError),
+    #[error("Amount imbalance: inputs {inputs} sat != outputs {outputs} sat + fee {fee} sat")]
+    AmountImbalance { inputs: u64, outputs: u64, fee: u64 },
+    #[error("Cannot reclaim funds: {0}")]
+    CannotReclaim(String),
+    #[error("Prevout not found: {0}")]
+    PrevoutNotFound(String),
+    #[error("Prevout {outpoint} holds {actual} sat on-chain, but {expected} sat was supplied")]
+    PrevoutValueMismatch {
+        outpoint: String,
+        expected: u64,
+        actual: u64,
+    },
+    #[error("Invalid fee margin: {0}")]
+    InvalidFeeMargin(String),
+    #[error("OP_RETURN payload is {0} bytes, the maximum is {MAX_OP_RETURN_SIZE}")]
+    OpReturnTooLarge(usize),
+    #[error("Invalid input sequence: {0}")]
+    InvalidSequence(String),
+    #[error("Invalid HTLC script tree: {0}")]
+    InvalidTree(String),
+    #[error("HTLC has no {0:?} leaf")]
+    BranchNotInTree(SpendBranch),
+    #[error("Insufficient funds: {available} available, {required} required")]
+    InsufficientFunds { available: Amount, required: Amount },
+    #[error("Redeem requires a preimage")]
+    MissingPreimage,
+    #[error("{0:?} spends are not supported here")]
+    UnsupportedBranch(SpendBranch),
+    #[error("Spend has {count} inputs, the maximum is {max}; split it into batches")]
+    TooManyInputs { count: usize, max: usize },
+    #[error("Preimage does not hash to the payment hash")]
+    PreimageMismatch,
+    #[error("Outpoint {outpoint} was already spent by {spending_txid}")]
+    AlreadySpent {
+        outpoint: String,
+        spending_txid: String,
+    },
+    #[error("Outpoint {outpoint} does not exist: the transaction has {output_count} outputs")]
+    VoutOutOfRange {
+        outpoint: String,
+        output_count: usize,
+    },
+    #[error("HTLC holds {actual} of confirmed funding, expected {expected} within {tolerance}")]
+    FundingAmountMismatch {
+        expected: Amount,
+        actual: Amount,
+        tolerance: Amount,
+    },
+    #[error("Destination script {0} is not a standard output type")]
+    NonStandardDestination(String),
+    #[error("Invalid burn: {0}")]
+    InvalidBurn(String),
+    #[error(
+        "Transaction version {0} does not enforce the relative timelock, at least 2 is required"
+    )]
+    InvalidTxVersion(i32),
+    #[error("HTLC address mismatch: parameters derive {expected}, got {actual}")]
+    AddressMismatch { expected: String, actual: String },
+    #[error("Fee estimate overflowed for {input_count} inputs and {output_count} outputs")]
+    FeeOverflow {
+        input_count: usize,
+        output_count: usize,
+    },
+}
+
+impl TaprootError {
+    /// Stable machine-readable code for this error.
+    pub fn code(&self) -> &'static str {
+        match self {
+            TaprootError::InvalidHtlcType(_) => "invalid_htlc_type",
+            TaprootError::InvalidTimelock => "invalid_timelock",
+            TaprootError::InvalidNumsPoint(_) => "invalid_nums_point",
+            TaprootError::TaprootBuildError => "taproot_build_error",
+            TaprootError::InvalidPaymentHash(_) => "invalid_payment_hash",
+            TaprootError::PushBytesBufError(_) => "push_bytes_error",
+            TaprootError::InvalidResponderPubkey(_) => "invalid_responder_pubkey",
+            TaprootError::InvalidInitiatorPubkey(_) => "invalid_initiator_pubkey",
+            TaprootError::InvalidRedeemerPubkey(_) => "invalid_redeemer_pubkey",
+            TaprootError::ControlBlockError => "control_block_error",
+            TaprootError::InvalidPreimage(_) => "invalid_preimage",
+            TaprootError::SighashError { .. } => "sighash_error",
+            TaprootError::InvalidTxid(_) => "invalid_txid",
+            TaprootError::InvalidPrivateKey(_) => "invalid_private_key",
+            TaprootError::PrivateKeyMismatch(_) => "private_key_mismatch",
+            TaprootError::TaprootBuilderError(_) => "taproot_builder_error",
+            TaprootError::MissingAbsoluteTimelock => "missing_absolute_timelock",
+            TaprootError::InvalidAbsoluteTimelock(_) => "invalid_absolute_timelock",
+            TaprootError::TimelockNotMature(_) => "timelock_not_mature",
+            TaprootError::BackendError(_) => "backend_error",
+            TaprootError::NoUtxos(_) => "no_utxos",
+            TaprootError::DuplicatePubkeys => "duplicate_pubkeys",
+            TaprootError::InvalidAddress(_) => "invalid_address",
+            TaprootError::NetworkMismatch(_) => "network_mismatch",
+            TaprootError::AmountImbalance { .. } => "amount_imbalance",
+            TaprootError::CannotReclaim(_) => "cannot_reclaim",
+            TaprootError::PrevoutNotFound(_) => "prevout_not_found",
+            TaprootError::PrevoutValueMismatch { .. } => "prevout_value_mismatch",
+            TaprootError::InvalidFeeMargin(_) => "invalid_fee_margin",
+            TaprootError::OpReturnTooLarge(_) => "op_return_too_large",
+            TaprootError::InvalidSequence(_) => "invalid_sequence",
+            TaprootError::InvalidTree(_) => "invalid_tree",
+            TaprootError::BranchNotInTree(_) => "branch_not_in_tree",
+            TaprootError::InsufficientFunds { .. } => "insufficient_funds",
+            TaprootError::MissingPreimage => "missing_preimage",
+            TaprootError::UnsupportedBranch(_) => "unsupported_branch",
+            TaprootError::TooManyInputs { .. } => "too_many_inputs",
+            TaprootError::PreimageMismatch => "preimage_mismatch",
+            TaprootError::FeeOverflow { .. } => "fee_overflow",
+            TaprootError::AlreadySpent { .. } => "already_spent",
+            TaprootError::VoutOutOfRange { .. } => "vout_out_of_range",
+            TaprootError::InvalidBurn(_) => "invalid_burn",
+            TaprootError::InvalidTxVersion(_) => "invalid_tx_version",
+            TaprootError::NonStandardDestination(_) => "non_standard_destination",
+            TaprootError::FundingAmountMismatch { .. } => "funding_amount_mismatch",
+            TaprootError::AddressMismatch { .. } => "address_mismatch",
+        }
+    }
+
+    /// Converts the error into a serializable `{ code, message }` pair without
+    /// exposing the internal `Debug` representation.
+    pub fn to_api_error(&self) -> ApiError {
+        ApiError::new(self.code(), self)
+    }
+}
+
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum AmountError {
+    #[error("Invalid BTC amount: '{0}'")]
+    InvalidFormat(String),
+    #[error("BTC amount has more than 8 decimals: '{0}'")]
+    TooPrecise(String),
+    #[error("BTC amount out of range: '{0}'")]
+    OutOfRange(String),
+}
+
+#[derive(Error, Debug)]
+pub enum SwapError {
+    #[error("Invalid HTLC")]
+    InvalidHtlc(#[source] TaprootError),
+    #[error("Invalid HTLC address")]
+    InvalidAddress(#[source] AddressParseError),
+    #[error("Backend request failed")]
+    BackendError(#[source] UtilsError),
+    #[error("No funding output recorded for this swap")]
+    NoFundingRecorded,
+    #[error("Funding output {0} not found among unspent outputs")]
+    FundingNotFound(String),
+    #[error("Funding output {outpoint} holds {actual} sat, but {expected} sat was recorded")]
+    FundingValueMismatch {
+        outpoint: String,
+        expected: u64,
+        actual: u64,
+    },
+    #[error("HTLC address mismatch: expected {expected}, got {actual}")]
+    HtlcAddressMismatch { expected: String, actual: String },
+    #[error("Invalid HTLC descriptor: {0}")]
+    InvalidDescriptor(String),
+    #[error("Failed to fund HTLC")]
+    FundingFailed(#[source] TaprootError),
+    #[error("Cannot tell how funding output {0} was spent")]
+    UnclassifiedSpend(String),
+    #[error("HTLCs lock different secrets: payment hash {a} vs {b}")]
+    SecretMismatch { a: String, b: String },
+    #[error("HTLCs hash the preimage differently: {a:?} vs {b:?}")]
+    HashKindMismatch { a: HashKind, b: HashKind },
+    #[error("Timelock {timelock} ({unit:?}) exceeds the maximum of {max}")]
+    TimelockTooLong {
+        timelock: u64,
+        max: u64,
+        unit: CsvUnit,
+    },
+}
+
+#[derive(Error, Debug)]
+pub enum RbfError {
+    #[error("Transaction has {inputs} inputs but {prevouts} prevouts were supplied")]
+    PrevoutCountMismatch { inputs: usize, prevouts: usize },
+    #[error("Fee bump must increase the fee")]
+    FeeNotIncreased,
+    #[error("Output {0} does not exist")]
+    InvalidOutputIndex(usize),
+    #[error("Output {index} holds {available}, but the bump needs {required}")]
+    InsufficientValue {
+        index: usize,
+        available: Amount,
+        required: Amount,
+    },
+    #[error("Output {0} would be dust after the bump")]
+    DustOutput(usize),
+    #[error("Cannot read signatures of input {index}: {reason}")]
+    UnsupportedInput { index: usize, reason: String },
+    #[error("Input {index} is signed with sighash {sighash:#04x}, but {reason}")]
+    IncompatibleSighash {
+        index: usize,
+        sighash: u8,
+        reason: String,
+    },
+    #[error("Replacement pays {replacement}, but at least {required} is required")]
+    InsufficientReplacementFee {
+        replacement: Amount,
+        required: Amount,
+    },
+    #[error(
+        "Replacement fee rate ({replacement_fee} for {replacement_vsize} vB) does not exceed \
+         the original ({original_fee} for {original_vsize} vB)"
+    )]
+    FeeRateNotIncreased {
+        original_fee: Amount,
+        original_vsize: u64,
+        replacement_fee: Amount,
+        replacement_vsize: u64,
+    },
+}
+
+#[cfg(feature = "ptlc")]
+#[derive(Error, Debug)]
+pub enum AdaptorError {
+    #[error("Failed to generate a nonce for the adaptor signature")]
+    NonceGeneration,
+    #[error("Signature nonce does not match the adaptor")]
+    NonceMismatch,
+    #[error("Scalar arithmetic failed: {0}")]
+    Arithmetic(String),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::error::Error;
+
+    #[test]
+    fn test_parse_failures_keep_source() {
+        let err = TaprootError::InvalidPreimage(hex::decode("zz").unwrap_err());
+        let source = err.source().expect("Expected a source error");
+        assert!(source.downcast_ref::<hex::FromHexError>().is_some());
+
+        let err =
+            crate::p2tr::script_pubkey_from_address("not-an-address", bitcoin::Network::Testnet)
+                .unwrap_err();
+        assert_eq!(err.code(), "invalid_address");
+        assert!(err.source().is_some());
+
+        let err = crate::tx_utils::derive_keypair("not-hex").unwrap_err();
+        assert!(err.source().is_some());
+    }
+}
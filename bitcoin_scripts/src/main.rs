@@ -2,6 +2,14 @@ mod utils;
 mod tx_utils;
 mod p2tr;
 mod swap;
+mod backend;
+mod error;
+mod amount;
+mod rbf;
+#[cfg(feature = "ptlc")]
+mod adaptor;
+#[cfg(test)]
+mod test_support;
 
 
 fn main() {
@@ -0,0 +1,335 @@
+use crate::error::RbfError;
+use crate::tx_utils::{DustPolicy, is_dust};
+use bitcoin::{Amount, Transaction, TxOut, Witness};
+use log::{error, info};
+
+/// Minimum fee rate, in sat/vB, a replacement must add to pay for its own relay
+/// (Bitcoin Core's default `-incrementalrelayfee`).
+const INCREMENTAL_RELAY_FEE_RATE: u64 = 1;
+
+// Base sighash types, shared by the ECDSA and Taproot encodings
+const SIGHASH_ALL: u8 = 0x01;
+const SIGHASH_NONE: u8 = 0x02;
+const SIGHASH_SINGLE: u8 = 0x03;
+const SIGHASH_ANYONECANPAY: u8 = 0x80;
+
+/// Bumps the fee of a signed transaction by taking `extra_fee` from output
+/// `change_index`, optionally appending `extra_outputs` funded from the same output.
+///
+/// Existing witnesses are kept, so every signature must still be valid for the
+/// replacement. The sighash type of each signature is read from the witness (P2TR and
+/// segwit v0 ECDSA) and the bump is refused if it touches anything a signature commits
+/// to: any output change under ALL, or the matching output under SINGLE. `prevouts`
+/// must hold the spent output of every input, in order.
+pub fn bump_fee(
+    tx: &Transaction,
+    prevouts: &[TxOut],
+    change_index: usize,
+    extra_fee: Amount,
+    extra_outputs: Vec<TxOut>,
+) -> Result<Transaction, RbfError> {
+    if prevouts.len() != tx.input.len() {
+        return Err(RbfError::PrevoutCountMismatch {
+            inputs: tx.input.len(),
+            prevouts: prevouts.len(),
+        });
+    }
+    if extra_fee == Amount::ZERO {
+        return Err(RbfError::FeeNotIncreased);
+    }
+
+    let mut bumped = tx.clone();
+    let change = bumped
+        .output
+        .get_mut(change_index)
+        .ok_or(RbfError::InvalidOutputIndex(change_index))?;
+    let required = extra_fee + extra_outputs.iter().map(|output| output.value).sum();
+    change.value = change
+        .value
+        .checked_sub(required)
+        .ok_or(RbfError::InsufficientValue {
+            index: change_index,
+            available: change.value,
+            required,
+        })?;
+    if is_dust(change, DustPolicy::Standard) {
+        error!(
+            "Bumped output {} would be dust: {}",
+            change_index, change.value
+        );
+        return Err(RbfError::DustOutput(change_index));
+    }
+    bumped.output.extend(extra_outputs);
+
+    check_signatures_still_valid(tx, &bumped, prevouts)?;
+    info!(
+        "Bumped fee of {} by {} from output {}",
+        tx.compute_txid(),
+        extra_fee,
+        change_index
+    );
+    Ok(bumped)
+}
+
+/// Checks that `replacement_tx` would be accepted in place of `original_tx` under
+/// BIP-125: it must pay at least the original's absolute fee plus the incremental
+/// relay fee for its own size (rules 3 and 4), and a strictly higher fee rate.
+pub fn validate_rbf_replacement(
+    original_tx: &Transaction,
+    original_fee: Amount,
+    replacement_tx: &Transaction,
+    replacement_fee: Amount,
+) -> Result<(), RbfError> {
+    let original_vsize = original_tx.vsize() as u64;
+    let replacement_vsize = replacement_tx.vsize() as u64;
+
+    let required = original_fee + Amount::from_sat(replacement_vsize * INCREMENTAL_RELAY_FEE_RATE);
+    if replacement_fee < required {
+        error!(
+            "Replacement pays {}, but at least {} is required",
+            replacement_fee, required
+        );
+        return Err(RbfError::InsufficientReplacementFee {
+            replacement: replacement_fee,
+            required,
+        });
+    }
+
+    // Compare fee / vsize by cross-multiplying, so no rounding is involved
+    if u128::from(replacement_fee.to_sat()) * u128::from(original_vsize)
+        <= u128::from(original_fee.to_sat()) * u128::from(replacement_vsize)
+    {
+        error!(
+            "Replacement fee rate does not exceed the original: {} / {} vB vs {} / {} vB",
+            replacement_fee, replacement_vsize, original_fee, original_vsize
+        );
+        return Err(RbfError::FeeRateNotIncreased {
+            original_fee,
+            original_vsize,
+            replacement_fee,
+            replacement_vsize,
+        });
+    }
+    info!(
+        "Replacement {} is valid: {} over {} vB",
+        replacement_tx.compute_txid(),
+        replacement_fee,
+        replacement_vsize
+    );
+    Ok(())
+}
+
+/// Checks that every signature in `original` commits only to parts of the transaction
+/// that `bumped` leaves unchanged.
+fn check_signatures_still_valid(
+    original: &Transaction,
+    bumped: &Transaction,
+    prevouts: &[TxOut],
+) -> Result<(), RbfError> {
+    let inputs_changed = original.input.len() != bumped.input.len()
+        || original
+            .input
+            .iter()
+            .zip(&bumped.input)
+            .any(|(a, b)| a.previous_output != b.previous_output || a.sequence != b.sequence);
+
+    for (index, (input, prevout)) in original.input.iter().zip(prevouts).enumerate() {
+        for sighash in witness_sighash_types(&input.witness, prevout)
+            .map_err(|reason| RbfError::UnsupportedInput { index, reason })?
+        {
+            let incompatible = |reason: &str| RbfError::IncompatibleSighash {
+                index,
+                sighash,
+                reason: reason.to_string(),
+            };
+            if inputs_changed && sighash & SIGHASH_ANYONECANPAY == 0 {
+                return Err(incompatible("inputs changed without ANYONECANPAY"));
+            }
+            match sighash & !SIGHASH_ANYONECANPAY {
+                SIGHASH_NONE => {}
+                SIGHASH_SINGLE => {
+                    if original.output.get(index) != bumped.output.get(index) {
+                        return Err(incompatible("the output at the input's index changed"));
+                    }
+                }
+                _ => {
+                    if original.output != bumped.output {
+                        return Err(incompatible("outputs changed"));
+                    }
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Sighash bytes of the signatures in a witness, using the ECDSA/Taproot encoding with
+/// SIGHASH_DEFAULT reported as ALL.
+fn witness_sighash_types(witness: &Witness, prevout: &TxOut) -> Result<Vec<u8>, String> {
+    let script_pubkey = &prevout.script_pubkey;
+    if script_pubkey.is_p2tr() {
+        let stack_len = if witness.taproot_leaf_script().is_some() {
+            // Skip the leaf script and control block
+            witness.len() - 2
+        } else {
+            witness.len()
+        } - usize::from(witness.taproot_annex().is_some());
+        return witness
+            .iter()
+            .take(stack_len)
+            .filter_map(|item| match item.len() {
+                64 => Some(Ok(SIGHASH_ALL)),
+                65 => Some(
+                    bitcoin::TapSighashType::from_consensus_u8(item[64])
+                        .map(|sighash| sighash as u8)
+                        .map_err(|e| e.to_string()),
+                ),
+                _ => None,
+            })
+            .collect();
+    }
+    if script_pubkey.is_p2wsh() || script_pubkey.is_p2wpkh() {
+        return Ok(witness
+            .iter()
+            .filter_map(|item| bitcoin::ecdsa::Signature::from_slice(item).ok())
+            .map(|signature| signature.sighash_type.to_u32() as u8)
+            .collect());
+    }
+    Err(format!("unsupported script type {}", script_pubkey))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tx_utils::{
+        build_input, build_transaction, compute_sighash, derive_keypair, new_secp_context,
+        sign_ecdsa,
+    };
+    use bitcoin::hashes::Hash;
+    use bitcoin::secp256k1::Message;
+    use bitcoin::{EcdsaSighashType, OutPoint, ScriptBuf, Txid, opcodes};
+
+    fn signed_p2wsh_tx(
+        outputs: Vec<TxOut>,
+        sighash_type: EcdsaSighashType,
+    ) -> (Transaction, Vec<TxOut>) {
+        let keypair =
+            derive_keypair("250bd3a0f83f249fcb9298b1a89458453f8b6301c3076d6f48f22a25d40899d3")
+                .unwrap();
+        let witness_script = ScriptBuf::builder()
+            .push_key(&bitcoin::PublicKey::new(keypair.public_key()))
+            .push_opcode(opcodes::all::OP_CHECKSIG)
+            .into_script();
+        let prevouts = vec![TxOut {
+            value: Amount::from_sat(50_000),
+            script_pubkey: ScriptBuf::new_p2wsh(&witness_script.wscript_hash()),
+        }];
+        let outpoint = OutPoint::new(Txid::all_zeros(), 0);
+        let mut tx = build_transaction(vec![build_input(outpoint, None, None)], outputs);
+        let sighash = compute_sighash(&tx, 0, &prevouts, &witness_script, sighash_type).unwrap();
+        let signature = sign_ecdsa(
+            &new_secp_context(),
+            &Message::from_digest(sighash),
+            &keypair,
+            sighash_type,
+        );
+        tx.input[0].witness.push(signature.to_vec());
+        tx.input[0].witness.push(witness_script.as_bytes());
+        (tx, prevouts)
+    }
+
+    fn output(sats: u64) -> TxOut {
+        TxOut {
+            value: Amount::from_sat(sats),
+            script_pubkey: ScriptBuf::new_p2wpkh(&bitcoin::WPubkeyHash::all_zeros()),
+        }
+    }
+
+    #[test]
+    fn test_bump_fee_respects_sighash_single() {
+        let (tx, prevouts) = signed_p2wsh_tx(
+            vec![output(20_000), output(29_000)],
+            EcdsaSighashType::Single,
+        );
+
+        // Input 0 signed only output 0, so adding an output funded from it breaks the signature
+        let result = bump_fee(
+            &tx,
+            &prevouts,
+            0,
+            Amount::from_sat(500),
+            vec![output(1_000)],
+        );
+        assert!(matches!(
+            result,
+            Err(RbfError::IncompatibleSighash {
+                index: 0,
+                sighash: 0x03,
+                ..
+            })
+        ));
+
+        // Taking the fee and the new output from output 1 leaves the signed output intact
+        let bumped = bump_fee(
+            &tx,
+            &prevouts,
+            1,
+            Amount::from_sat(500),
+            vec![output(1_000)],
+        )
+        .unwrap();
+        assert_eq!(bumped.output[0], tx.output[0]);
+        assert_eq!(bumped.output[1].value, Amount::from_sat(27_500));
+        assert_eq!(bumped.output.len(), 3);
+        assert_eq!(bumped.input[0].witness, tx.input[0].witness);
+    }
+
+    #[test]
+    fn test_validate_rbf_replacement() {
+        let (tx, prevouts) = signed_p2wsh_tx(
+            vec![output(20_000), output(29_000)],
+            EcdsaSighashType::Single,
+        );
+        let original_fee = Amount::from_sat(1_000);
+        let vsize = tx.vsize() as u64;
+
+        // Bumping by the incremental relay fee is the minimum accepted
+        let bumped = bump_fee(&tx, &prevouts, 1, Amount::from_sat(vsize), vec![]).unwrap();
+        let bumped_fee = original_fee + Amount::from_sat(vsize);
+        assert!(validate_rbf_replacement(&tx, original_fee, &bumped, bumped_fee).is_ok());
+
+        let short = bumped_fee - Amount::from_sat(1);
+        assert!(matches!(
+            validate_rbf_replacement(&tx, original_fee, &bumped, short),
+            Err(RbfError::InsufficientReplacementFee { replacement, required })
+                if replacement == short && required == bumped_fee
+        ));
+        assert!(matches!(
+            validate_rbf_replacement(&tx, original_fee, &tx, original_fee),
+            Err(RbfError::InsufficientReplacementFee { .. })
+        ));
+
+        // A larger replacement can pay more in total yet less per vbyte
+        let mut larger = bumped.clone();
+        larger.output.extend((0..20).map(|_| output(1_000)));
+        let larger_fee = original_fee + Amount::from_sat(larger.vsize() as u64);
+        assert!(matches!(
+            validate_rbf_replacement(&tx, original_fee, &larger, larger_fee),
+            Err(RbfError::FeeRateNotIncreased { .. })
+        ));
+    }
+
+    #[test]
+    fn test_bump_fee_refuses_sighash_all() {
+        let (tx, prevouts) =
+            signed_p2wsh_tx(vec![output(20_000), output(29_000)], EcdsaSighashType::All);
+        let result = bump_fee(&tx, &prevouts, 1, Amount::from_sat(500), vec![]);
+        assert!(matches!(
+            result,
+            Err(RbfError::IncompatibleSighash { sighash: 0x01, .. })
+        ));
+
+        let result = bump_fee(&tx, &prevouts, 1, Amount::from_sat(29_000), vec![]);
+        assert!(matches!(result, Err(RbfError::DustOutput(1))));
+    }
+}
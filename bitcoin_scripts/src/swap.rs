@@ -1,4 +1,35 @@
+use crate::backend::Backend;
+use crate::error::SwapError;
+use crate::p2tr::{
+    FeeStrategy, SpendBranch, build_funding_tx, classify_spend, generate_p2tr_address, parse_txid,
+    refund_blocks_remaining,
+};
+use crate::tx_utils::new_secp_context;
+use crate::utils::Utxo;
+use bitcoin::consensus::{Decodable, Encodable};
+use bitcoin::hashes::{Hash, sha256, sha256d};
+use bitcoin::key::Keypair;
+use bitcoin::{Address, Amount, KnownHrp, Network, OutPoint, Txid, VarInt};
+use log::{error, info};
 use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::str::FromStr;
+use std::time::Duration;
+
+// Three 32-byte fields, a 1-byte varint timelock, type byte, u64 amount and flags byte
+const COMPACT_DESCRIPTOR_MIN_SIZE: usize = 96 + 1 + 1 + 8 + 1;
+
+const COMPACT_FLAG_CSV_SECONDS: u8 = 0x01;
+const COMPACT_FLAG_ABSOLUTE_TIMELOCK: u8 = 0x02;
+const COMPACT_FLAG_HASHLOCK_ONLY: u8 = 0x04;
+const COMPACT_FLAG_SHA256D: u8 = 0x08;
+const COMPACT_FLAG_SORTED_MULTISIG: u8 = 0x10;
+const COMPACT_FLAG_REDEEMER: u8 = 0x20;
+
+// Default ceiling on the refund timelock: the largest block-based CSV value (~455 days)
+// and its time-based equivalent in 512-second units
+const DEFAULT_MAX_TIMELOCK_BLOCKS: u64 = 0xffff;
+const DEFAULT_MAX_TIMELOCK_SECONDS: u64 = 0xffff * 512;
 
 // Define the enum for HTLC types
 #[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
@@ -7,12 +38,1197 @@ pub enum HTLCType {
     P2wsh2, // p2wsh with 2 spending path
 }
 
+/// Unit of the relative (OP_CSV) refund timelock.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Copy, Default)]
+pub enum CsvUnit {
+    #[default]
+    Blocks, // timelock counts blocks
+    Seconds, // timelock counts seconds, rounded up to 512-second intervals
+}
+
+/// Hash function the redeem leaf applies to the preimage.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Copy, Default)]
+pub enum HashKind {
+    #[default]
+    Sha256, // OP_SHA256, as used by Lightning
+    Sha256d, // OP_HASH256, double SHA256
+}
+
+impl HashKind {
+    /// Hashes `preimage` the way the redeem leaf does.
+    pub fn digest(&self, preimage: &[u8]) -> [u8; 32] {
+        match self {
+            HashKind::Sha256 => sha256::Hash::hash(preimage).to_byte_array(),
+            HashKind::Sha256d => sha256d::Hash::hash(preimage).to_byte_array(),
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
 pub struct Bitcoin {
     pub initiator_pubkey: String, // No Option, use "" as default
     pub responder_pubkey: String, // No Option, use "" as default
     pub timelock: u64,
     pub amount: u64,
-    pub htlc_type: HTLCType, // Required HTLC type for Bitcoin
+    pub htlc_type: HTLCType,  // Required HTLC type for Bitcoin
     pub payment_hash: String, // Required payment hash
+    #[serde(default)]
+    pub absolute_timelock: Option<u32>, // Optional CLTV block height for an absolute refund path
+    #[serde(default)]
+    pub csv_unit: CsvUnit, // Unit of `timelock`, blocks unless set
+    #[serde(default)]
+    pub hashlock_only: bool, // Redeem leaf only, with no refund paths
+    #[serde(default)]
+    pub hash_kind: HashKind, // Hash of the payment preimage, SHA256 unless set
+    #[serde(default)]
+    pub sorted_multisig: bool, // Lexicographically sorted keys in the instant refund leaf
+    #[serde(default)]
+    pub redeemer_pubkey: Option<String>, // Instant refund co-signer, responder_pubkey unless set
+}
+
+/// Limits an HTLC must respect before it is funded, guarding against typos that would
+/// lock the refund path for years.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Copy)]
+pub struct SwapPolicy {
+    pub max_timelock_blocks: u64,
+    pub max_timelock_seconds: u64,
+}
+
+impl Default for SwapPolicy {
+    fn default() -> Self {
+        SwapPolicy {
+            max_timelock_blocks: DEFAULT_MAX_TIMELOCK_BLOCKS,
+            max_timelock_seconds: DEFAULT_MAX_TIMELOCK_SECONDS,
+        }
+    }
+}
+
+/// Persisted state of a swap's Bitcoin leg.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct SwapState {
+    pub bitcoin: Bitcoin,
+    pub network: Network,
+    pub htlc_address: String,
+    pub funding_utxo: Option<Utxo>, // Recorded once the HTLC has been funded
+    /// Funding transaction broadcast by [`fund_once`], so a restart does not fund twice.
+    #[serde(default)]
+    pub funding_txid: Option<Txid>,
+}
+
+impl SwapState {
+    /// Creates the state for a new swap, rejecting HTLCs outside the default [`SwapPolicy`].
+    pub fn new(bitcoin: Bitcoin, network: Network) -> Result<SwapState, SwapError> {
+        bitcoin.validate(&SwapPolicy::default())?;
+        let (htlc_address, _) = generate_p2tr_address(&bitcoin, KnownHrp::from(network))
+            .map_err(SwapError::InvalidHtlc)?;
+        Ok(SwapState {
+            bitcoin,
+            network,
+            htlc_address: htlc_address.to_string(),
+            funding_utxo: None,
+            funding_txid: None,
+        })
+    }
+
+    /// Parses the stored HTLC address, checking it against the swap's network.
+    pub fn address(&self) -> Result<Address, SwapError> {
+        Address::from_str(&self.htlc_address)
+            .map_err(SwapError::InvalidAddress)?
+            .require_network(self.network)
+            .map_err(SwapError::InvalidAddress)
+    }
+}
+
+/// Re-checks a recorded funding output before resuming a swap: the stored address must
+/// still be the one derived from the HTLC, and the exact outpoint must be unspent with
+/// the recorded value.
+pub async fn verify_funding<B: Backend>(state: &SwapState, backend: &B) -> Result<(), SwapError> {
+    let funding = state
+        .funding_utxo
+        .as_ref()
+        .ok_or(SwapError::NoFundingRecorded)?;
+    let outpoint = format!("{}:{}", funding.txid, funding.vout);
+
+    let (expected_address, _) =
+        generate_p2tr_address(&state.bitcoin, KnownHrp::from(state.network))
+            .map_err(SwapError::InvalidHtlc)?;
+    let address = state.address()?;
+    if address != expected_address {
+        error!(
+            "Stored HTLC address {} does not match derived {}",
+            address, expected_address
+        );
+        return Err(SwapError::HtlcAddressMismatch {
+            expected: expected_address.to_string(),
+            actual: address.to_string(),
+        });
+    }
+
+    let utxos = backend
+        .fetch_utxos_for_address(&address)
+        .await
+        .map_err(SwapError::BackendError)?;
+    let current = utxos
+        .iter()
+        .find(|utxo| utxo.txid == funding.txid && utxo.vout == funding.vout)
+        .ok_or_else(|| {
+            error!("Recorded funding output {} is no longer unspent", outpoint);
+            SwapError::FundingNotFound(outpoint.clone())
+        })?;
+    if current.value != funding.value {
+        error!(
+            "Funding output {} holds {} sat, state recorded {} sat",
+            outpoint, current.value, funding.value
+        );
+        return Err(SwapError::FundingValueMismatch {
+            outpoint,
+            expected: funding.value,
+            actual: current.value,
+        });
+    }
+    info!("Verified funding output {}", outpoint);
+    Ok(())
+}
+
+/// Funds the swap's HTLC unless a funding transaction was already broadcast for it.
+///
+/// Returns the recorded `funding_txid` without building anything when one is set.
+/// Otherwise the funding transaction is built and broadcast, and its txid is recorded
+/// only once the broadcast succeeds. Persist `state` afterwards.
+pub async fn fund_once<B: Backend>(
+    state: &mut SwapState,
+    backend: &B,
+    funding_private_key: &str,
+    funding_utxos: Vec<Utxo>,
+    amount: Amount,
+    change_address: &Address,
+    fee_strategy: &FeeStrategy,
+) -> Result<Txid, SwapError> {
+    if let Some(txid) = state.funding_txid {
+        info!("Swap already funded by {}, not funding again", txid);
+        return Ok(txid);
+    }
+    let fee_rate = fee_strategy
+        .resolve(backend)
+        .await
+        .map_err(SwapError::FundingFailed)?;
+    let tx = build_funding_tx(
+        &new_secp_context(),
+        &state.bitcoin,
+        funding_private_key,
+        funding_utxos,
+        amount,
+        change_address,
+        fee_rate,
+        KnownHrp::from(state.network),
+    )
+    .map_err(SwapError::FundingFailed)?;
+    backend
+        .broadcast_trx(&bitcoin::consensus::encode::serialize_hex(&tx))
+        .await
+        .map_err(SwapError::BackendError)?;
+    let txid = tx.compute_txid();
+    state.funding_txid = Some(txid);
+    info!("Broadcast funding transaction {}", txid);
+    Ok(txid)
+}
+
+/// How often [`fund_and_confirm`] polls for the funding output's confirmations.
+const FUNDING_POLL_INTERVAL: Duration = Duration::from_secs(30);
+
+/// An HTLC whose funding output has reached the requested depth.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FundedHtlc {
+    pub address: Address,
+    pub funding_utxo: Utxo,
+    pub confirmations: u32,
+}
+
+/// Funds the HTLC from `funding_utxos`, broadcasts the funding transaction and waits
+/// until its HTLC output is `confirmations` blocks deep, ready to be spent.
+///
+/// See [`build_funding_tx`] for the funding inputs this expects.
+#[allow(clippy::too_many_arguments)]
+pub async fn fund_and_confirm<B: Backend>(
+    backend: &B,
+    funding_private_key: &str,
+    funding_utxos: Vec<Utxo>,
+    bitcoin: &Bitcoin,
+    amount: Amount,
+    change_address: &Address,
+    fee_strategy: &FeeStrategy,
+    confirmations: u32,
+    network: Network,
+) -> Result<FundedHtlc, SwapError> {
+    fund_and_confirm_every(
+        backend,
+        funding_private_key,
+        funding_utxos,
+        bitcoin,
+        amount,
+        change_address,
+        fee_strategy,
+        confirmations,
+        network,
+        FUNDING_POLL_INTERVAL,
+    )
+    .await
+}
+
+/// [`fund_and_confirm`] with a custom interval between confirmation polls.
+#[allow(clippy::too_many_arguments)]
+pub async fn fund_and_confirm_every<B: Backend>(
+    backend: &B,
+    funding_private_key: &str,
+    funding_utxos: Vec<Utxo>,
+    bitcoin: &Bitcoin,
+    amount: Amount,
+    change_address: &Address,
+    fee_strategy: &FeeStrategy,
+    confirmations: u32,
+    network: Network,
+    poll_interval: Duration,
+) -> Result<FundedHtlc, SwapError> {
+    let fee_rate = fee_strategy
+        .resolve(backend)
+        .await
+        .map_err(SwapError::FundingFailed)?;
+    let tx = build_funding_tx(
+        &new_secp_context(),
+        bitcoin,
+        funding_private_key,
+        funding_utxos,
+        amount,
+        change_address,
+        fee_rate,
+        KnownHrp::from(network),
+    )
+    .map_err(SwapError::FundingFailed)?;
+    let (address, _) =
+        generate_p2tr_address(bitcoin, KnownHrp::from(network)).map_err(SwapError::InvalidHtlc)?;
+
+    backend
+        .broadcast_trx(&bitcoin::consensus::encode::serialize_hex(&tx))
+        .await
+        .map_err(SwapError::BackendError)?;
+    let txid = tx.compute_txid().to_string();
+    // The HTLC output comes first, ahead of any change
+    let outpoint = format!("{}:0", txid);
+    info!(
+        "Broadcast funding transaction {}, waiting for {} confirmations",
+        txid, confirmations
+    );
+
+    loop {
+        let utxos = backend
+            .fetch_utxos_for_address(&address)
+            .await
+            .map_err(SwapError::BackendError)?;
+        let funding_utxo = utxos
+            .into_iter()
+            .find(|utxo| utxo.txid == txid && utxo.vout == 0);
+        if let Some(funding_utxo) = funding_utxo {
+            let depth = if funding_utxo.status.confirmed {
+                let tip_height = backend
+                    .fetch_tip_block_height()
+                    .await
+                    .map_err(SwapError::BackendError)?;
+                (tip_height + 1).saturating_sub(funding_utxo.status.block_height)
+            } else {
+                0
+            };
+            if depth >= confirmations {
+                info!("Funding output {} is {} blocks deep", outpoint, depth);
+                return Ok(FundedHtlc {
+                    address,
+                    funding_utxo,
+                    confirmations: depth,
+                });
+            }
+            info!(
+                "Funding output {} has {} of {} confirmations",
+                outpoint, depth, confirmations
+            );
+        } else {
+            info!("Funding output {} not seen yet", outpoint);
+        }
+        tokio::time::sleep(poll_interval).await;
+    }
+}
+
+/// Lifecycle phase of an HTLC.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Copy)]
+pub enum SwapPhase {
+    Unfunded,
+    FundingUnconfirmed,
+    Funded,
+    Spent,
+}
+
+/// Point-in-time status of a swap, for dashboards.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct SwapReport {
+    pub phase: SwapPhase,
+    pub funded_amount: u64,
+    pub confirmations: u32,
+    pub blocks_until_refundable: Option<u64>,
+    pub tip_height: u32,
+}
+
+impl fmt::Display for SwapReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Phase: {:?}", self.phase)?;
+        writeln!(f, "Funded amount: {} sat", self.funded_amount)?;
+        writeln!(f, "Confirmations: {}", self.confirmations)?;
+        match self.blocks_until_refundable {
+            Some(0) => writeln!(f, "Refund: available now")?,
+            Some(blocks) => writeln!(f, "Refund: in {} blocks", blocks)?,
+            None => writeln!(f, "Refund: n/a")?,
+        }
+        write!(f, "Tip height: {}", self.tip_height)
+    }
+}
+
+/// Number of swaps in one phase and the sats they hold (or held, once spent).
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Copy, Default)]
+pub struct PhaseTotal {
+    pub count: usize,
+    pub amount: u64,
+}
+
+/// Aggregate of many swaps' [`SwapReport`]s, grouped by phase, with spent swaps split
+/// by the branch that spent them.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Default)]
+pub struct PortfolioReport {
+    pub unfunded: PhaseTotal,
+    pub funding_unconfirmed: PhaseTotal,
+    pub funded: PhaseTotal,
+    pub redeemed: PhaseTotal,
+    /// Spent through the timelock or the instant refund branch.
+    pub refunded: PhaseTotal,
+}
+
+impl PortfolioReport {
+    /// Sats currently sitting in HTLC outputs, confirmed or not.
+    pub fn locked_amount(&self) -> u64 {
+        self.funding_unconfirmed.amount + self.funded.amount
+    }
+}
+
+/// Runs [`swap_status`] for every swap and sums the results by phase. Spent swaps are
+/// counted as redeemed or refunded with [`funding_spend_branch`].
+pub async fn portfolio_summary<B: Backend>(
+    states: &[SwapState],
+    backend: &B,
+) -> Result<PortfolioReport, SwapError> {
+    let mut portfolio = PortfolioReport::default();
+    for state in states {
+        let report = swap_status(state, backend).await?;
+        let total = match report.phase {
+            SwapPhase::Unfunded => &mut portfolio.unfunded,
+            SwapPhase::FundingUnconfirmed => &mut portfolio.funding_unconfirmed,
+            SwapPhase::Funded => &mut portfolio.funded,
+            SwapPhase::Spent => match funding_spend_branch(state, backend).await? {
+                SpendBranch::Redeem => &mut portfolio.redeemed,
+                SpendBranch::Refund | SpendBranch::InstantRefund => &mut portfolio.refunded,
+            },
+        };
+        total.count += 1;
+        total.amount += report.funded_amount;
+    }
+    info!(
+        "Portfolio of {} swaps has {} sat locked",
+        states.len(),
+        portfolio.locked_amount()
+    );
+    Ok(portfolio)
+}
+
+/// Branch through which the swap's recorded funding output was spent, read from the
+/// spending transaction the backend reports for it.
+pub async fn funding_spend_branch<B: Backend>(
+    state: &SwapState,
+    backend: &B,
+) -> Result<SpendBranch, SwapError> {
+    let funding = state
+        .funding_utxo
+        .as_ref()
+        .ok_or(SwapError::NoFundingRecorded)?;
+    let txid = parse_txid(&funding.txid).map_err(SwapError::InvalidHtlc)?;
+    let outpoint = OutPoint::new(txid, funding.vout);
+    let spending_txid = backend
+        .is_outpoint_spent(&txid, funding.vout)
+        .await
+        .map_err(SwapError::BackendError)?;
+    let spending_tx = match spending_txid {
+        Some(spending_txid) => backend
+            .fetch_transaction(&spending_txid)
+            .await
+            .map_err(SwapError::BackendError)?,
+        None => None,
+    };
+    let branch = spending_tx.and_then(|tx| {
+        let input_index = tx
+            .input
+            .iter()
+            .position(|input| input.previous_output == outpoint)?;
+        classify_spend(&tx, input_index, &state.bitcoin)
+    });
+    branch.ok_or_else(|| {
+        error!("Cannot classify the spend of funding output {}", outpoint);
+        SwapError::UnclassifiedSpend(outpoint.to_string())
+    })
+}
+
+/// Reports whether the HTLC is funded, how deep the funding is, whether it has been
+/// spent, and how many blocks remain until the refund path opens.
+pub async fn swap_status<B: Backend>(
+    state: &SwapState,
+    backend: &B,
+) -> Result<SwapReport, SwapError> {
+    let address = state.address()?;
+    let utxos = backend
+        .fetch_utxos_for_address(&address)
+        .await
+        .map_err(SwapError::BackendError)?;
+    let tip_height = backend
+        .fetch_tip_block_height()
+        .await
+        .map_err(SwapError::BackendError)?;
+
+    if utxos.is_empty() {
+        // A recorded funding output that is gone from the UTXO set has been spent
+        let phase = match &state.funding_utxo {
+            Some(_) => SwapPhase::Spent,
+            None => SwapPhase::Unfunded,
+        };
+        return Ok(SwapReport {
+            phase,
+            funded_amount: state.funding_utxo.as_ref().map_or(0, |utxo| utxo.value),
+            confirmations: 0,
+            blocks_until_refundable: None,
+            tip_height,
+        });
+    }
+
+    let funded_amount = utxos.iter().map(|utxo| utxo.value).sum();
+    if utxos.iter().any(|utxo| !utxo.status.confirmed) {
+        return Ok(SwapReport {
+            phase: SwapPhase::FundingUnconfirmed,
+            funded_amount,
+            confirmations: 0,
+            blocks_until_refundable: None,
+            tip_height,
+        });
+    }
+
+    // Report the least-confirmed output, since the refund needs all of them mature
+    let newest_height = utxos
+        .iter()
+        .map(|utxo| utxo.status.block_height)
+        .max()
+        .unwrap_or(tip_height);
+    Ok(SwapReport {
+        phase: SwapPhase::Funded,
+        funded_amount,
+        confirmations: (tip_height + 1).saturating_sub(newest_height),
+        blocks_until_refundable: Some(refund_blocks_remaining(
+            &state.bitcoin,
+            newest_height,
+            tip_height,
+        )),
+        tip_height,
+    })
+}
+
+impl Bitcoin {
+    /// Builds a P2TR HTLC from Lightning invoice parameters.
+    ///
+    /// The invoice's CLTV expiry delta is relative to when the HTLC is set up, so it
+    /// maps onto the relative (CSV) refund timelock rather than an absolute height.
+    pub fn from_lightning(
+        payment_hash: &str,
+        cltv_delta: u32,
+        initiator_pubkey: &str,
+        responder_pubkey: &str,
+        amount: u64,
+    ) -> Bitcoin {
+        Bitcoin {
+            initiator_pubkey: initiator_pubkey.to_string(),
+            responder_pubkey: responder_pubkey.to_string(),
+            timelock: cltv_delta as u64,
+            amount,
+            htlc_type: HTLCType::P2tr2,
+            payment_hash: payment_hash.to_lowercase(),
+            absolute_timelock: None,
+            csv_unit: CsvUnit::Blocks,
+            hashlock_only: false,
+            hash_kind: HashKind::Sha256,
+            sorted_multisig: false,
+            redeemer_pubkey: None,
+        }
+    }
+
+    /// Checks the refund timelock against the limits of `policy` for its [`CsvUnit`].
+    pub fn validate(&self, policy: &SwapPolicy) -> Result<(), SwapError> {
+        let max = match self.csv_unit {
+            CsvUnit::Blocks => policy.max_timelock_blocks,
+            CsvUnit::Seconds => policy.max_timelock_seconds,
+        };
+        if self.timelock > max {
+            error!(
+                "Timelock {} ({:?}) exceeds the maximum of {}",
+                self.timelock, self.csv_unit, max
+            );
+            return Err(SwapError::TimelockTooLong {
+                timelock: self.timelock,
+                max,
+                unit: self.csv_unit,
+            });
+        }
+        Ok(())
+    }
+
+    /// Sets the responder's x-only pubkey from the keypair the responder holds.
+    pub fn with_responder_keypair(mut self, keypair: &Keypair) -> Bitcoin {
+        self.responder_pubkey = keypair.x_only_public_key().0.to_string();
+        self
+    }
+
+    /// Sets the initiator's x-only pubkey from the keypair the initiator holds.
+    pub fn with_initiator_keypair(mut self, keypair: &Keypair) -> Bitcoin {
+        self.initiator_pubkey = keypair.x_only_public_key().0.to_string();
+        self
+    }
+
+    /// Key co-signing the instant refund with the initiator: `redeemer_pubkey` if set,
+    /// otherwise the responder's.
+    pub fn redeemer_key(&self) -> &str {
+        self.redeemer_pubkey
+            .as_deref()
+            .unwrap_or(&self.responder_pubkey)
+    }
+
+    /// Whether `preimage` hashes to the payment hash under the HTLC's [`HashKind`].
+    pub fn verify_preimage(&self, preimage: &[u8]) -> bool {
+        hex::decode(&self.payment_hash)
+            .is_ok_and(|payment_hash| payment_hash == self.hash_kind.digest(preimage))
+    }
+
+    /// Packs the HTLC descriptor into a compact binary form for QR codes and other
+    /// constrained channels. Layout:
+    ///
+    /// `initiator_pubkey (32) | responder_pubkey (32) | payment_hash (32) |
+    /// timelock (varint) | htlc_type (1) | amount (u64 LE) | flags (1) |
+    /// absolute_timelock (u32 LE, only if flagged) | redeemer_pubkey (32, only if flagged)`
+    pub fn to_compact_bytes(&self) -> Result<Vec<u8>, SwapError> {
+        let decode_32 = |field: &str, value: &str| {
+            hex::decode(value)
+                .ok()
+                .filter(|decoded| decoded.len() == 32)
+                .ok_or_else(|| {
+                    SwapError::InvalidDescriptor(format!("{} must be 32 bytes of hex", field))
+                })
+        };
+        let mut bytes = Vec::with_capacity(COMPACT_DESCRIPTOR_MIN_SIZE + 8 + 4 + 32);
+        for (field, value) in [
+            ("initiator_pubkey", &self.initiator_pubkey),
+            ("responder_pubkey", &self.responder_pubkey),
+            ("payment_hash", &self.payment_hash),
+        ] {
+            bytes.extend_from_slice(&decode_32(field, value)?);
+        }
+        VarInt(self.timelock)
+            .consensus_encode(&mut bytes)
+            .map_err(|e| SwapError::InvalidDescriptor(e.to_string()))?;
+        bytes.push(match self.htlc_type {
+            HTLCType::P2tr2 => 0,
+            HTLCType::P2wsh2 => 1,
+        });
+        bytes.extend_from_slice(&self.amount.to_le_bytes());
+
+        let mut flags = 0u8;
+        if self.csv_unit == CsvUnit::Seconds {
+            flags |= COMPACT_FLAG_CSV_SECONDS;
+        }
+        if self.absolute_timelock.is_some() {
+            flags |= COMPACT_FLAG_ABSOLUTE_TIMELOCK;
+        }
+        if self.hashlock_only {
+            flags |= COMPACT_FLAG_HASHLOCK_ONLY;
+        }
+        if self.hash_kind == HashKind::Sha256d {
+            flags |= COMPACT_FLAG_SHA256D;
+        }
+        if self.sorted_multisig {
+            flags |= COMPACT_FLAG_SORTED_MULTISIG;
+        }
+        if self.redeemer_pubkey.is_some() {
+            flags |= COMPACT_FLAG_REDEEMER;
+        }
+        bytes.push(flags);
+        if let Some(absolute_timelock) = self.absolute_timelock {
+            bytes.extend_from_slice(&absolute_timelock.to_le_bytes());
+        }
+        if let Some(redeemer_pubkey) = &self.redeemer_pubkey {
+            bytes.extend_from_slice(&decode_32("redeemer_pubkey", redeemer_pubkey)?);
+        }
+        Ok(bytes)
+    }
+
+    /// Decodes a descriptor produced by [`Bitcoin::to_compact_bytes`].
+    pub fn from_compact_bytes(bytes: &[u8]) -> Result<Bitcoin, SwapError> {
+        let invalid = |reason: &str| SwapError::InvalidDescriptor(reason.to_string());
+        if bytes.len() < COMPACT_DESCRIPTOR_MIN_SIZE {
+            return Err(invalid("descriptor too short"));
+        }
+        let (keys, mut rest) = bytes.split_at(96);
+        let VarInt(timelock) =
+            VarInt::consensus_decode(&mut rest).map_err(|e| invalid(&e.to_string()))?;
+
+        let mut take = |len: usize| -> Result<&[u8], SwapError> {
+            if rest.len() < len {
+                return Err(invalid("descriptor truncated"));
+            }
+            let (head, tail) = rest.split_at(len);
+            rest = tail;
+            Ok(head)
+        };
+        let htlc_type = match take(1)?[0] {
+            0 => HTLCType::P2tr2,
+            1 => HTLCType::P2wsh2,
+            other => return Err(invalid(&format!("unknown HTLC type {}", other))),
+        };
+        let amount = u64::from_le_bytes(take(8)?.try_into().expect("8 bytes"));
+        let flags = take(1)?[0];
+        let known_flags = COMPACT_FLAG_CSV_SECONDS
+            | COMPACT_FLAG_ABSOLUTE_TIMELOCK
+            | COMPACT_FLAG_HASHLOCK_ONLY
+            | COMPACT_FLAG_SHA256D
+            | COMPACT_FLAG_SORTED_MULTISIG
+            | COMPACT_FLAG_REDEEMER;
+        if flags & !known_flags != 0 {
+            return Err(invalid(&format!("unknown flags {:#04x}", flags)));
+        }
+        let absolute_timelock = if flags & COMPACT_FLAG_ABSOLUTE_TIMELOCK != 0 {
+            Some(u32::from_le_bytes(take(4)?.try_into().expect("4 bytes")))
+        } else {
+            None
+        };
+        let redeemer_pubkey = if flags & COMPACT_FLAG_REDEEMER != 0 {
+            Some(hex::encode(take(32)?))
+        } else {
+            None
+        };
+        if !rest.is_empty() {
+            return Err(invalid("trailing bytes after descriptor"));
+        }
+
+        Ok(Bitcoin {
+            initiator_pubkey: hex::encode(&keys[..32]),
+            responder_pubkey: hex::encode(&keys[32..64]),
+            timelock,
+            amount,
+            htlc_type,
+            payment_hash: hex::encode(&keys[64..]),
+            absolute_timelock,
+            csv_unit: if flags & COMPACT_FLAG_CSV_SECONDS != 0 {
+                CsvUnit::Seconds
+            } else {
+                CsvUnit::Blocks
+            },
+            hashlock_only: flags & COMPACT_FLAG_HASHLOCK_ONLY != 0,
+            hash_kind: if flags & COMPACT_FLAG_SHA256D != 0 {
+                HashKind::Sha256d
+            } else {
+                HashKind::Sha256
+            },
+            sorted_multisig: flags & COMPACT_FLAG_SORTED_MULTISIG != 0,
+            redeemer_pubkey,
+        })
+    }
+}
+
+/// Checks that two legs of a cross-chain swap are unlocked by the same preimage: both
+/// must carry the same payment hash under the same [`HashKind`].
+pub fn assert_same_secret(htlc_a: &Bitcoin, htlc_b: &Bitcoin) -> Result<(), SwapError> {
+    if htlc_a.hash_kind != htlc_b.hash_kind {
+        error!(
+            "HTLCs hash the preimage differently: {:?} vs {:?}",
+            htlc_a.hash_kind, htlc_b.hash_kind
+        );
+        return Err(SwapError::HashKindMismatch {
+            a: htlc_a.hash_kind,
+            b: htlc_b.hash_kind,
+        });
+    }
+    if !htlc_a
+        .payment_hash
+        .eq_ignore_ascii_case(&htlc_b.payment_hash)
+    {
+        error!(
+            "HTLCs lock different secrets: {} vs {}",
+            htlc_a.payment_hash, htlc_b.payment_hash
+        );
+        return Err(SwapError::SecretMismatch {
+            a: htlc_a.payment_hash.clone(),
+            b: htlc_b.payment_hash.clone(),
+        });
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::mock::MockBackend;
+    use crate::p2tr::{redeem_taproot_htlc, refund_taproot_htlc};
+    use crate::test_support::{
+        INITIATOR_PRIVATE_KEY, PREIMAGE, RESPONDER_PRIVATE_KEY, deterministic_keypair,
+        mock_bitcoin, test_destination,
+    };
+    use crate::tx_utils::derive_keypair;
+    use crate::utils::UtxoStatus;
+
+    fn mock_utxo(confirmed: bool, block_height: u32, value: u64) -> Utxo {
+        Utxo {
+            txid: "9c8a5fc42f8f57537f3a2746be78632f6ff2f0bb65a87c196496db50970c5787".to_string(),
+            vout: 0,
+            value,
+            status: UtxoStatus {
+                confirmed,
+                block_height,
+                block_hash: "0000000000000000000000000000000000000000000000000000000000000000"
+                    .to_string(),
+                block_time: 1234567890,
+            },
+        }
+    }
+
+    #[tokio::test]
+    async fn test_swap_status_phases() {
+        let mut state = SwapState::new(mock_bitcoin(), Network::Testnet).unwrap();
+        assert_eq!(
+            state.htlc_address,
+            "tb1px4qe74pc66rklv2pvk59xszsfff6xnkuluhzt3te23hdgaawtuqque804v"
+        );
+        let backend = MockBackend::with_tip(2316000);
+
+        let report = swap_status(&state, &backend).await.unwrap();
+        assert_eq!(report.phase, SwapPhase::Unfunded);
+        assert_eq!(report.funded_amount, 0);
+
+        backend.set_utxos(vec![mock_utxo(false, 0, 10000)]);
+        let report = swap_status(&state, &backend).await.unwrap();
+        assert_eq!(report.phase, SwapPhase::FundingUnconfirmed);
+        assert_eq!(report.funded_amount, 10000);
+        assert_eq!(report.blocks_until_refundable, None);
+
+        let funding = mock_utxo(true, 2315994, 10000);
+        backend.set_utxos(vec![funding.clone()]);
+        let report = swap_status(&state, &backend).await.unwrap();
+        assert_eq!(report.phase, SwapPhase::Funded);
+        assert_eq!(report.confirmations, 7);
+        assert_eq!(report.blocks_until_refundable, Some(137));
+        assert!(report.to_string().contains("Refund: in 137 blocks"));
+
+        state.funding_utxo = Some(funding);
+        backend.set_utxos(vec![]);
+        let report = swap_status(&state, &backend).await.unwrap();
+        assert_eq!(report.phase, SwapPhase::Spent);
+        assert_eq!(report.funded_amount, 10000);
+    }
+
+    #[tokio::test]
+    async fn test_portfolio_summary() {
+        let swap = |byte: u8, amount: u64| {
+            let mut bitcoin = mock_bitcoin();
+            bitcoin.payment_hash = hex::encode([byte; 32]);
+            bitcoin.amount = amount;
+            SwapState::new(bitcoin, Network::Testnet).unwrap()
+        };
+        let unfunded = swap(1, 1000);
+        let unconfirmed = swap(2, 2000);
+        let funded = swap(3, 3000);
+
+        let backend = MockBackend::with_tip(2316000);
+        backend.set_address_utxos(&unfunded.htlc_address, vec![]);
+        backend.set_address_utxos(&unconfirmed.htlc_address, vec![mock_utxo(false, 0, 2000)]);
+        backend.set_address_utxos(&funded.htlc_address, vec![mock_utxo(true, 2315994, 3000)]);
+
+        let states = [unfunded, unconfirmed, funded.clone(), funded];
+        let portfolio = portfolio_summary(&states, &backend).await.unwrap();
+        assert_eq!(
+            portfolio.unfunded,
+            PhaseTotal {
+                count: 1,
+                amount: 0
+            }
+        );
+        assert_eq!(
+            portfolio.funding_unconfirmed,
+            PhaseTotal {
+                count: 1,
+                amount: 2000
+            }
+        );
+        assert_eq!(
+            portfolio.funded,
+            PhaseTotal {
+                count: 2,
+                amount: 6000
+            }
+        );
+        assert_eq!(portfolio.locked_amount(), 8000);
+
+        assert_eq!(
+            portfolio_summary(&[], &backend).await.unwrap(),
+            PortfolioReport::default()
+        );
+    }
+
+    /// Records `tx` with `backend` as the spend of its first input's outpoint.
+    fn record_spend(backend: &MockBackend, tx: bitcoin::Transaction) {
+        backend.mark_spent(tx.input[0].previous_output, tx.compute_txid());
+        backend.add_transaction(tx);
+    }
+
+    #[tokio::test]
+    async fn test_portfolio_summary_counts_redeemed_swaps() {
+        let mut state = SwapState::new(mock_bitcoin(), Network::Testnet).unwrap();
+        let funding = mock_utxo(true, 2315990, 4000);
+        state.funding_utxo = Some(funding.clone());
+        let states = [state];
+        let backend = MockBackend::with_tip(2316000);
+
+        // The funding output is gone, but the backend does not know the spend yet
+        assert!(matches!(
+            portfolio_summary(&states, &backend).await,
+            Err(SwapError::UnclassifiedSpend(_))
+        ));
+
+        let redeem = redeem_taproot_htlc(
+            &states[0].bitcoin,
+            PREIMAGE,
+            RESPONDER_PRIVATE_KEY,
+            vec![funding],
+            &test_destination(),
+            2,
+            KnownHrp::Testnets,
+        )
+        .unwrap();
+        record_spend(&backend, redeem);
+        let portfolio = portfolio_summary(&states, &backend).await.unwrap();
+        assert_eq!(
+            portfolio.redeemed,
+            PhaseTotal {
+                count: 1,
+                amount: 4000
+            }
+        );
+        assert_eq!(portfolio.refunded, PhaseTotal::default());
+        assert_eq!(portfolio.locked_amount(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_portfolio_summary_counts_refunded_swaps() {
+        let mut state = SwapState::new(mock_bitcoin(), Network::Testnet).unwrap();
+        let funding = mock_utxo(true, 2315990, 5000);
+        state.funding_utxo = Some(funding.clone());
+        let backend = MockBackend::with_tip(2316000);
+        let refund = refund_taproot_htlc(
+            &state.bitcoin,
+            INITIATOR_PRIVATE_KEY,
+            vec![funding],
+            &test_destination(),
+            2,
+            KnownHrp::Testnets,
+        )
+        .unwrap();
+        record_spend(&backend, refund);
+
+        let portfolio = portfolio_summary(&[state], &backend).await.unwrap();
+        assert_eq!(
+            portfolio.refunded,
+            PhaseTotal {
+                count: 1,
+                amount: 5000
+            }
+        );
+        assert_eq!(portfolio.redeemed, PhaseTotal::default());
+    }
+
+    #[test]
+    fn test_with_keypairs_sets_x_only_pubkeys() {
+        let initiator = derive_keypair(INITIATOR_PRIVATE_KEY).unwrap();
+        let responder = derive_keypair(RESPONDER_PRIVATE_KEY).unwrap();
+        let expected = mock_bitcoin();
+
+        let mut bitcoin = mock_bitcoin();
+        bitcoin.initiator_pubkey = String::new();
+        bitcoin.responder_pubkey = String::new();
+        let bitcoin = bitcoin
+            .with_initiator_keypair(&initiator)
+            .with_responder_keypair(&responder);
+        assert_eq!(bitcoin.initiator_pubkey, expected.initiator_pubkey);
+        assert_eq!(bitcoin.responder_pubkey, expected.responder_pubkey);
+        assert_eq!(
+            bitcoin.responder_pubkey,
+            responder.x_only_public_key().0.to_string()
+        );
+        assert_eq!(bitcoin, expected);
+    }
+
+    #[test]
+    fn test_validate_rejects_absurd_timelock() {
+        let mut bitcoin = mock_bitcoin();
+        assert!(bitcoin.validate(&SwapPolicy::default()).is_ok());
+
+        // ~19 years of blocks
+        bitcoin.timelock = 1_000_000;
+        assert!(matches!(
+            bitcoin.validate(&SwapPolicy::default()),
+            Err(SwapError::TimelockTooLong {
+                timelock: 1_000_000,
+                max: 65_535,
+                unit: CsvUnit::Blocks
+            })
+        ));
+        assert!(SwapState::new(bitcoin.clone(), Network::Testnet).is_err());
+
+        // The same value in seconds is under 12 days
+        bitcoin.csv_unit = CsvUnit::Seconds;
+        assert!(bitcoin.validate(&SwapPolicy::default()).is_ok());
+
+        let strict = SwapPolicy {
+            max_timelock_blocks: 1_008,
+            ..Default::default()
+        };
+        let mut bitcoin = mock_bitcoin();
+        assert!(bitcoin.validate(&strict).is_ok());
+        bitcoin.timelock = 1_009;
+        assert!(bitcoin.validate(&strict).is_err());
+    }
+
+    #[test]
+    fn test_from_lightning_maps_invoice_fields() {
+        // payment hash and min_final_cltv_expiry_delta as decoded from a BOLT11 invoice
+        let bitcoin = Bitcoin::from_lightning(
+            "1572A86FB4B1F15623DA10E34034FD151090D37E6F0F3EF4F69926F7F3388B78",
+            144,
+            "456db773aa5c4cc6ed3a4780243d16bd58220be318702603b219fe79eceb848f",
+            "f1946d446157bc98699db7271d2fe9495ea4bcf25eb81b645c89803e18af9a22",
+            10000,
+        );
+
+        assert_eq!(
+            bitcoin.payment_hash,
+            "1572a86fb4b1f15623da10e34034fd151090d37e6f0f3ef4f69926f7f3388b78"
+        );
+        assert_eq!(bitcoin.timelock, 144);
+        assert_eq!(bitcoin.absolute_timelock, None);
+        assert_eq!(bitcoin.csv_unit, CsvUnit::Blocks);
+        assert_eq!(bitcoin.amount, 10000);
+        assert_eq!(bitcoin.htlc_type, HTLCType::P2tr2);
+    }
+
+    #[test]
+    fn test_assert_same_secret() {
+        let leg_a = mock_bitcoin();
+        let mut leg_b = Bitcoin::from_lightning(
+            &leg_a.payment_hash.to_uppercase(),
+            40,
+            &leg_a.responder_pubkey,
+            &leg_a.initiator_pubkey,
+            50_000,
+        );
+        assert!(assert_same_secret(&leg_a, &leg_b).is_ok());
+
+        leg_b.payment_hash = sha256::Hash::hash(b"other secret").to_string();
+        assert!(matches!(
+            assert_same_secret(&leg_a, &leg_b),
+            Err(SwapError::SecretMismatch { .. })
+        ));
+
+        leg_b.payment_hash = leg_a.payment_hash.clone();
+        leg_b.hash_kind = HashKind::Sha256d;
+        assert!(matches!(
+            assert_same_secret(&leg_a, &leg_b),
+            Err(SwapError::HashKindMismatch {
+                a: HashKind::Sha256,
+                b: HashKind::Sha256d
+            })
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_verify_funding_detects_stale_state() {
+        let mut state = SwapState::new(mock_bitcoin(), Network::Testnet).unwrap();
+        let backend = MockBackend::with_tip(2316000);
+        assert!(matches!(
+            verify_funding(&state, &backend).await,
+            Err(SwapError::NoFundingRecorded)
+        ));
+
+        let funding = mock_utxo(true, 2315994, 10000);
+        state.funding_utxo = Some(funding.clone());
+        backend.set_utxos(vec![funding.clone()]);
+        verify_funding(&state, &backend)
+            .await
+            .expect("Expected funding to verify");
+
+        backend.set_utxos(vec![mock_utxo(true, 2315994, 9000)]);
+        assert!(matches!(
+            verify_funding(&state, &backend).await,
+            Err(SwapError::FundingValueMismatch {
+                expected: 10000,
+                actual: 9000,
+                ..
+            })
+        ));
+
+        // The recorded output has been spent
+        backend.set_utxos(vec![]);
+        assert!(matches!(
+            verify_funding(&state, &backend).await,
+            Err(SwapError::FundingNotFound(_))
+        ));
+    }
+
+    #[test]
+    fn test_compact_descriptor_round_trip() {
+        let bitcoin = mock_bitcoin();
+        let bytes = bitcoin.to_compact_bytes().unwrap();
+        // 96 key/hash bytes + 1-byte varint(144) + type + amount + flags
+        assert_eq!(bytes.len(), COMPACT_DESCRIPTOR_MIN_SIZE);
+        assert_eq!(Bitcoin::from_compact_bytes(&bytes).unwrap(), bitcoin);
+
+        let mut extended = mock_bitcoin();
+        extended.timelock = 10;
+        extended.csv_unit = CsvUnit::Seconds;
+        extended.absolute_timelock = Some(2_400_000);
+        let bytes = extended.to_compact_bytes().unwrap();
+        assert_eq!(bytes.len(), 96 + 1 + 1 + 8 + 1 + 4);
+        assert_eq!(Bitcoin::from_compact_bytes(&bytes).unwrap(), extended);
+
+        let mut hashlock_only = mock_bitcoin();
+        hashlock_only.hashlock_only = true;
+        let bytes = hashlock_only.to_compact_bytes().unwrap();
+        assert_eq!(Bitcoin::from_compact_bytes(&bytes).unwrap(), hashlock_only);
+
+        assert!(matches!(
+            Bitcoin::from_compact_bytes(&bytes[..bytes.len() - 1]),
+            Err(SwapError::InvalidDescriptor(_))
+        ));
+        let mut invalid = mock_bitcoin();
+        invalid.payment_hash = "abcd".to_string();
+        assert!(matches!(
+            invalid.to_compact_bytes(),
+            Err(SwapError::InvalidDescriptor(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_fund_once_funds_a_single_time() {
+        let mut state = SwapState::new(mock_bitcoin(), Network::Testnet).unwrap();
+        let (funding_key, _) = deterministic_keypair(5);
+        let funding_private_key = funding_key.display_secret().to_string();
+        let change_address = test_destination();
+        let amount = Amount::from_sat(state.bitcoin.amount);
+        let fee_strategy = FeeStrategy::Fixed(2.into());
+        let backend = MockBackend::with_tip(100);
+
+        let first = fund_once(
+            &mut state,
+            &backend,
+            &funding_private_key,
+            vec![mock_utxo(true, 90, 50_000)],
+            amount,
+            &change_address,
+            &fee_strategy,
+        )
+        .await
+        .unwrap();
+        assert_eq!(state.funding_txid, Some(first));
+
+        // A restarted orchestrator replaying the step, even with other coins, is a no-op
+        let second = fund_once(
+            &mut state,
+            &backend,
+            &funding_private_key,
+            vec![mock_utxo(true, 91, 60_000)],
+            amount,
+            &change_address,
+            &fee_strategy,
+        )
+        .await
+        .unwrap();
+        assert_eq!(second, first);
+
+        let broadcasts = backend.broadcasts.lock().unwrap();
+        assert_eq!(broadcasts.len(), 1);
+        let tx: bitcoin::Transaction =
+            bitcoin::consensus::encode::deserialize_hex(&broadcasts[0]).unwrap();
+        assert_eq!(tx.compute_txid(), first);
+    }
+
+    #[tokio::test]
+    async fn test_fund_and_confirm_waits_for_depth() {
+        let bitcoin = mock_bitcoin();
+        let network = Network::Testnet;
+        let (funding_key, funding_pubkey) = deterministic_keypair(5);
+        let funding_private_key = funding_key.display_secret().to_string();
+        let change_address = test_destination();
+        let funding_utxos = vec![mock_utxo(true, 90, 50_000)];
+        let fee_strategy = FeeStrategy::Fixed(2.into());
+        let amount = Amount::from_sat(bitcoin.amount);
+
+        // Signing is deterministic, so the txid is known before funding
+        let expected_tx = build_funding_tx(
+            &new_secp_context(),
+            &bitcoin,
+            &funding_private_key,
+            funding_utxos.clone(),
+            amount,
+            &change_address,
+            2,
+            KnownHrp::from(network),
+        )
+        .unwrap();
+        let mut htlc_utxo = mock_utxo(true, 100, bitcoin.amount);
+        htlc_utxo.txid = expected_tx.compute_txid().to_string();
+        let backend = MockBackend::with_tip(100);
+        backend.set_utxos(vec![htlc_utxo.clone()]);
+        *backend.tip_advance.lock().unwrap() = 1;
+
+        let funded = fund_and_confirm_every(
+            &backend,
+            &funding_private_key,
+            funding_utxos,
+            &bitcoin,
+            amount,
+            &change_address,
+            &fee_strategy,
+            3,
+            network,
+            Duration::ZERO,
+        )
+        .await
+        .unwrap();
+        assert_eq!(funded.funding_utxo, htlc_utxo);
+        assert_eq!(funded.confirmations, 3);
+        assert_eq!(*backend.tip_fetches.lock().unwrap(), 3);
+
+        let broadcasts = backend.broadcasts.lock().unwrap();
+        assert_eq!(broadcasts.len(), 1);
+        let tx: bitcoin::Transaction =
+            bitcoin::consensus::encode::deserialize_hex(&broadcasts[0]).unwrap();
+        assert_eq!(tx.output[0].script_pubkey, funded.address.script_pubkey());
+        assert_eq!(tx.output[1].script_pubkey, change_address.script_pubkey());
+        let prevouts = vec![bitcoin::TxOut {
+            value: Amount::from_sat(50_000),
+            script_pubkey: bitcoin::ScriptBuf::new_p2tr(&new_secp_context(), funding_pubkey, None),
+        }];
+        crate::tx_utils::verify_tx(&tx, &prevouts).expect("Expected a valid funding tx");
+    }
 }
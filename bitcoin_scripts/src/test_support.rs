@@ -0,0 +1,132 @@
+//! Shared fixtures for tests: deterministic keys and the reference HTLC.
+
+use crate::swap::{Bitcoin, CsvUnit, HTLCType, HashKind};
+use crate::utils::{Utxo, UtxoStatus};
+use bitcoin::hashes::{Hash, sha256};
+use bitcoin::secp256k1::{Secp256k1, SecretKey};
+use bitcoin::{Address, XOnlyPublicKey};
+
+/// Private key of the initiator (refunding party) in [`mock_bitcoin`].
+pub const INITIATOR_PRIVATE_KEY: &str =
+    "c929c768be0902d5bb7ae6e38bdc6b3b24cefbe93650da91975756a09e408460";
+
+/// Private key of the responder (redeeming party) in [`mock_bitcoin`].
+pub const RESPONDER_PRIVATE_KEY: &str =
+    "250bd3a0f83f249fcb9298b1a89458453f8b6301c3076d6f48f22a25d40899d3";
+
+/// Preimage of the payment hash in [`mock_bitcoin`].
+pub const PREIMAGE: &str = "e235db8c009db64dcd2b6ab8295afc024f46c23c24e1dde0e984fd08cdb47a91";
+
+/// Txid of the funding transaction used by [`mock_htlc_utxo`].
+pub const MOCK_FUNDING_TXID: &str =
+    "9c8a5fc42f8f57537f3a2746be78632f6ff2f0bb65a87c196496db50970c5787";
+
+/// Height at which [`mock_htlc_utxo`] is confirmed.
+pub const MOCK_FUNDING_HEIGHT: u32 = 2315994;
+
+/// Keypair derived from `seed` as `sha256([seed])`, so every seed maps to a fixed,
+/// valid key.
+pub fn deterministic_keypair(seed: u8) -> (SecretKey, XOnlyPublicKey) {
+    let digest = sha256::Hash::hash(&[seed]);
+    let secret_key =
+        SecretKey::from_slice(digest.as_byte_array()).expect("sha256 output is a valid key");
+    let (pubkey, _) = secret_key.x_only_public_key(&Secp256k1::new());
+    (secret_key, pubkey)
+}
+
+/// The 144-block, 10000 sat HTLC used throughout the tests, between the keys above.
+pub fn mock_bitcoin() -> Bitcoin {
+    Bitcoin {
+        initiator_pubkey: "456db773aa5c4cc6ed3a4780243d16bd58220be318702603b219fe79eceb848f"
+            .to_string(),
+        responder_pubkey: "f1946d446157bc98699db7271d2fe9495ea4bcf25eb81b645c89803e18af9a22"
+            .to_string(),
+        timelock: 144,
+        amount: 10000,
+        htlc_type: HTLCType::P2tr2,
+        payment_hash: "1572a86fb4b1f15623da10e34034fd151090d37e6f0f3ef4f69926f7f3388b78"
+            .to_string(),
+        absolute_timelock: None,
+        csv_unit: CsvUnit::Blocks,
+        hashlock_only: false,
+        hash_kind: HashKind::Sha256,
+        sorted_multisig: false,
+        redeemer_pubkey: None,
+    }
+}
+
+/// Confirmed UTXO of `value` sats at `txid:vout`, mined at `block_height`.
+pub fn create_mock_utxo(block_height: u32, txid: &str, vout: u32, value: u64) -> Utxo {
+    Utxo {
+        txid: txid.to_string(),
+        vout,
+        value,
+        status: UtxoStatus {
+            confirmed: true,
+            block_height,
+            block_hash: "0000000000000000000000000000000000000000000000000000000000000000"
+                .to_string(),
+            block_time: 1234567890,
+        },
+    }
+}
+
+/// Funding output of `value` sats at `MOCK_FUNDING_TXID:0`, standing in for the HTLC's
+/// UTXO.
+pub fn mock_htlc_utxo(value: u64) -> Utxo {
+    create_mock_utxo(MOCK_FUNDING_HEIGHT, MOCK_FUNDING_TXID, 0, value)
+}
+
+/// Testnet P2WPKH address used as the destination of test spends.
+pub fn test_destination() -> Address {
+    "tb1qw00nzjpepd3kvq384vezwxqhmedhm578x3mxjv"
+        .parse::<Address<_>>()
+        .expect("valid testnet address")
+        .assume_checked()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tx_utils::derive_keypair;
+
+    #[test]
+    fn test_mock_bitcoin_matches_fixture_keys() {
+        let bitcoin = mock_bitcoin();
+        let x_only = |key| {
+            derive_keypair(key)
+                .unwrap()
+                .x_only_public_key()
+                .0
+                .to_string()
+        };
+        assert_eq!(x_only(INITIATOR_PRIVATE_KEY), bitcoin.initiator_pubkey);
+        assert_eq!(x_only(RESPONDER_PRIVATE_KEY), bitcoin.responder_pubkey);
+
+        let preimage = hex::decode(PREIMAGE).unwrap();
+        assert_eq!(
+            sha256::Hash::hash(&preimage).to_string(),
+            bitcoin.payment_hash
+        );
+        assert_eq!(
+            Bitcoin::from_lightning(
+                &bitcoin.payment_hash,
+                144,
+                &bitcoin.initiator_pubkey,
+                &bitcoin.responder_pubkey,
+                10000,
+            ),
+            bitcoin
+        );
+    }
+
+    #[test]
+    fn test_deterministic_keypair() {
+        let (secret_key, pubkey) = deterministic_keypair(1);
+        assert_eq!(deterministic_keypair(1), (secret_key, pubkey));
+        assert_ne!(deterministic_keypair(2).1, pubkey);
+
+        let keypair = derive_keypair(&secret_key.display_secret().to_string()).unwrap();
+        assert_eq!(keypair.x_only_public_key().0, pubkey);
+    }
+}
@@ -1,27 +1,38 @@
 
-use bitcoin::key::Keypair;
-use bitcoin::secp256k1::{Message, Secp256k1, SecretKey};
-use bitcoin::sighash::{Prevouts, SighashCache};
+use crate::error::TxUtilsError;
+use bitcoin::bip32::{DerivationPath, Xpriv};
+use bitcoin::hashes::{Hash, sha256, sha256d};
+use bitcoin::key::{Keypair, PrivateKey};
+use bitcoin::opcodes::{self, Opcode};
+use bitcoin::script::Instruction;
+use bitcoin::secp256k1::{All, Message, Secp256k1, SecretKey, schnorr};
+use bitcoin::sighash::{Annex, Prevouts, SighashCache};
+use bitcoin::taproot::{ControlBlock, LeafVersion, TAPROOT_ANNEX_PREFIX};
+use bitcoin::transaction::Version;
 use bitcoin::{
-    Address, Amount, OutPoint, ScriptBuf, Sequence, TapLeafHash, TapSighashType, Transaction, TxIn,
-    TxOut, Witness, EcdsaSighashType,
+    Address, Amount, EcdsaSighashType, FeeRate, Network, NetworkKind, OutPoint, Script, ScriptBuf,
+    Sequence, TapLeafHash, TapSighashType, Transaction, TxIn, TxOut, Weight, Witness,
+    XOnlyPublicKey,
 };
 use log::{error, info};
 use std::str::FromStr;
-use thiserror::Error;
 
-#[derive(Error, Debug)]
-pub enum TxUtilsError {
-    #[error("Invalid private key: {0}")]
-    InvalidPrivateKey(String),
-    #[error("Failed to compute Taproot sighash: {0}")]
-    SighashComputationError(String),
-}
+// Code separator position committed to when no OP_CODESEPARATOR was executed
+const NO_CODESEPARATOR: u32 = 0xffff_ffff;
 
 /// Builds a basic transaction with given inputs and outputs.
 pub fn build_transaction(inputs: Vec<TxIn>, outputs: Vec<TxOut>) -> Transaction {
+    build_transaction_with_version(inputs, outputs, None)
+}
+
+/// Like [`build_transaction`], with an explicit version instead of the default `Version::TWO`.
+pub fn build_transaction_with_version(
+    inputs: Vec<TxIn>,
+    outputs: Vec<TxOut>,
+    version: Option<Version>,
+) -> Transaction {
     let tx = Transaction {
-        version: bitcoin::transaction::Version::TWO,
+        version: version.unwrap_or(Version::TWO),
         lock_time: bitcoin::locktime::absolute::LockTime::ZERO,
         input: inputs,
         output: outputs,
@@ -35,9 +46,18 @@ pub fn build_transaction(inputs: Vec<TxIn>, outputs: Vec<TxOut>) -> Transaction
 }
 
 /// Creates a transaction input.
-pub fn build_input(prev_txid: OutPoint, sequence: Option<u32>) -> TxIn {
-    let sequence = sequence.map_or(Sequence::ENABLE_RBF_NO_LOCKTIME, |s| {
-        Sequence::from_height(s as u16)
+///
+/// `sequence_override` sets nSequence verbatim and takes precedence over the
+/// height-derived `sequence`.
+pub fn build_input(
+    prev_txid: OutPoint,
+    sequence: Option<u32>,
+    sequence_override: Option<Sequence>,
+) -> TxIn {
+    let sequence = sequence_override.unwrap_or_else(|| {
+        sequence.map_or(Sequence::ENABLE_RBF_NO_LOCKTIME, |s| {
+            Sequence::from_height(s as u16)
+        })
     });
     let input = TxIn {
         previous_output: prev_txid,
@@ -62,20 +82,70 @@ pub fn build_output(value: Amount, address: &Address) -> TxOut {
     output
 }
 
-/// Computes the Taproot script spend sighash.
+/// How [`is_dust`] decides whether an output is too small to relay.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum DustPolicy {
+    /// Bitcoin Core's standard rule at the default 3 sat/vB dust relay fee.
+    #[default]
+    Standard,
+    /// The standard rule evaluated at a custom dust relay fee rate.
+    RelayFeeRate(FeeRate),
+    /// A fixed minimum value, for scripts whose dust economics differ from the
+    /// standard ones.
+    Threshold(Amount),
+}
+
+/// Returns true if `output` is below the dust limit of `policy`.
+pub fn is_dust(output: &TxOut, policy: DustPolicy) -> bool {
+    let threshold = match policy {
+        DustPolicy::Standard => output.script_pubkey.minimal_non_dust(),
+        DustPolicy::RelayFeeRate(fee_rate) => {
+            output.script_pubkey.minimal_non_dust_custom(fee_rate)
+        }
+        DustPolicy::Threshold(threshold) => threshold,
+    };
+    output.value < threshold
+}
+
+/// Value of an output once the fee to spend it at `fee_rate` is paid, where
+/// `input_weight` is the full weight of the spending input (its 41 non-witness bytes
+/// plus the witness). Unlike [`is_dust`], this depends on the current fee rate: a
+/// negative effective value means the output costs more to spend than it is worth.
+pub fn effective_value(value: Amount, input_weight: Weight, fee_rate: FeeRate) -> i64 {
+    let fee = fee_rate.fee_wu(input_weight).unwrap_or(Amount::MAX_MONEY);
+    value.to_sat() as i64 - fee.to_sat() as i64
+}
+
+/// Fee rate in sat/vB a signed transaction actually pays: `(sum_in - sum_out) / vsize`.
+/// `prevouts` must hold the spent output of every input. A negative result means the
+/// prevouts are wrong, since outputs cannot exceed inputs.
+pub fn effective_fee_rate(tx: &Transaction, prevouts: &[TxOut]) -> f64 {
+    let inputs: u64 = prevouts.iter().map(|prevout| prevout.value.to_sat()).sum();
+    let outputs: u64 = tx.output.iter().map(|output| output.value.to_sat()).sum();
+    (inputs as f64 - outputs as f64) / tx.vsize() as f64
+}
+
+/// Computes the Taproot script spend sighash. `annex` is the full annex witness element,
+/// including its 0x50 prefix.
 pub fn compute_taproot_sighash(
     tx: &Transaction,
     input_index: usize,
     prevouts: &[TxOut],
     leaf_hash: TapLeafHash,
     sighash_type: TapSighashType,
+    annex: Option<&[u8]>,
 ) -> Result<Message, TxUtilsError> {
+    let annex = annex
+        .map(Annex::new)
+        .transpose()
+        .map_err(|e| TxUtilsError::SighashComputationError(e.to_string()))?;
     let mut sighash_cache = SighashCache::new(tx);
     let sighash = sighash_cache
-        .taproot_script_spend_signature_hash(
+        .taproot_signature_hash(
             input_index,
             &Prevouts::All(prevouts),
-            leaf_hash,
+            annex,
+            Some((leaf_hash, NO_CODESEPARATOR)),
             sighash_type,
         )
         .map_err(|e| {
@@ -92,6 +162,25 @@ pub fn compute_taproot_sighash(
     })
 }
 
+/// Computes the Taproot key-path spend sighash.
+pub fn compute_taproot_key_sighash(
+    tx: &Transaction,
+    input_index: usize,
+    prevouts: &[TxOut],
+    sighash_type: TapSighashType,
+) -> Result<Message, TxUtilsError> {
+    let sighash = SighashCache::new(tx)
+        .taproot_key_spend_signature_hash(input_index, &Prevouts::All(prevouts), sighash_type)
+        .map_err(|e| {
+            error!(
+                "Failed to compute Taproot key spend sighash for input {}: {}",
+                input_index, e
+            );
+            TxUtilsError::SighashComputationError(e.to_string())
+        })?;
+    Ok(Message::from_digest(sighash.to_byte_array()))
+}
+
 /// Signs a Taproot sighash with a Schnorr signature.
 pub fn sign_schnorr(
     secp: &Secp256k1<bitcoin::secp256k1::All>,
@@ -103,23 +192,80 @@ pub fn sign_schnorr(
     signature
 }
 
+#[cfg(test)]
+thread_local! {
+    /// Number of signing contexts created through `new_secp_context` on this thread.
+    pub(crate) static SECP_CONTEXTS_CREATED: std::cell::Cell<usize> = const { std::cell::Cell::new(0) };
+}
+
+/// Creates a signing context. Contexts are costly to build, so hot paths should take a
+/// shared `&Secp256k1<All>` instead of calling this per operation.
+pub fn new_secp_context() -> Secp256k1<All> {
+    #[cfg(test)]
+    SECP_CONTEXTS_CREATED.with(|count| count.set(count.get() + 1));
+    Secp256k1::new()
+}
+
 /// Derives a keypair from a private key string.
 pub fn derive_keypair(private_key: &str) -> Result<Keypair, TxUtilsError> {
+    derive_keypair_with_secp(&new_secp_context(), private_key)
+}
+
+/// [`derive_keypair`] using a caller-provided context.
+pub fn derive_keypair_with_secp(
+    secp: &Secp256k1<All>,
+    private_key: &str,
+) -> Result<Keypair, TxUtilsError> {
     let secret_key = SecretKey::from_str(private_key).map_err(|e| {
         error!("Invalid private key: {}", e);
-        TxUtilsError::InvalidPrivateKey(e.to_string())
+        TxUtilsError::InvalidPrivateKey(e)
     })?;
-    let keypair = Keypair::from_secret_key(&Secp256k1::new(), &secret_key);
+    let keypair = Keypair::from_secret_key(secp, &secret_key);
     info!("Derived keypair from private key");
     Ok(keypair)
 }
 
+/// Derives a keypair from a BIP32 extended private key and a derivation path (e.g. `m/86'/1'/0'/0/0`).
+pub fn derive_keypair_from_xprv(xprv: &str, path: &str) -> Result<Keypair, TxUtilsError> {
+    let xprv = Xpriv::from_str(xprv).map_err(|e| {
+        error!("Invalid extended private key: {}", e);
+        TxUtilsError::InvalidExtendedPrivateKey(e)
+    })?;
+    let path = DerivationPath::from_str(path).map_err(|e| {
+        error!("Invalid derivation path: {}", e);
+        TxUtilsError::InvalidDerivationPath(e)
+    })?;
+    let secp = new_secp_context();
+    let derived = xprv.derive_priv(&secp, &path).map_err(|e| {
+        error!("Failed to derive key at {}: {}", path, e);
+        TxUtilsError::InvalidDerivationPath(e)
+    })?;
+    info!("Derived keypair from extended private key at {}", path);
+    Ok(derived.to_keypair(&secp))
+}
+
+/// Derives a keypair from a WIF-encoded private key, as exported by most wallets,
+/// returning the network kind the WIF was encoded for alongside it.
+pub fn derive_keypair_from_wif(wif: &str) -> Result<(Keypair, NetworkKind), TxUtilsError> {
+    let private_key = PrivateKey::from_wif(wif).map_err(|e| {
+        error!("Invalid WIF private key: {}", e);
+        TxUtilsError::InvalidWif(e)
+    })?;
+    let keypair = Keypair::from_secret_key(&new_secp_context(), &private_key.inner);
+    info!(
+        "Derived keypair from {:?} WIF private key",
+        private_key.network
+    );
+    Ok((keypair, private_key.network))
+}
+
 /// Computes the P2WSH sighash for witness script spending.
 pub fn compute_sighash(
     tx: &Transaction,
     input_index: usize,
     prevouts: &[TxOut],
     witness_script: &ScriptBuf,
+    sighash_type: EcdsaSighashType,
 ) -> Result<[u8; 32], TxUtilsError> {
     let mut sighash_cache = SighashCache::new(tx);
     let sighash = sighash_cache
@@ -127,7 +273,7 @@ pub fn compute_sighash(
             input_index,
             witness_script,
             prevouts[input_index].value,
-            EcdsaSighashType::All,
+            sighash_type,
         )
         .map_err(|e| {
             error!(
@@ -142,16 +288,812 @@ pub fn compute_sighash(
     Ok(bytes)
 }
 
-/// Signs an ECDSA sighash.
+/// Nonce generation for ECDSA signatures.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum EcdsaNonce {
+    /// Plain RFC6979 deterministic nonce, the secp256k1 default.
+    #[default]
+    Rfc6979,
+    /// RFC6979 with extra entropy, retried until R has its high bit clear. Still
+    /// deterministic, and the signature is one byte shorter at most.
+    LowR,
+}
+
+/// Signs an ECDSA sighash. `sighash_type` must match the one the sighash was computed
+/// with; it is appended to the serialized signature.
 pub fn sign_ecdsa(
     secp: &Secp256k1<bitcoin::secp256k1::All>,
     msg: &Message,
     keypair: &Keypair,
+    sighash_type: EcdsaSighashType,
 ) -> bitcoin::ecdsa::Signature {
-    let signature = secp.sign_ecdsa(msg, &keypair.secret_key());
-    info!("Generated ECDSA signature for message");
+    sign_ecdsa_with_nonce(secp, msg, keypair, sighash_type, EcdsaNonce::Rfc6979)
+}
+
+/// [`sign_ecdsa`] with the nonce generation chosen by `nonce`.
+pub fn sign_ecdsa_with_nonce(
+    secp: &Secp256k1<bitcoin::secp256k1::All>,
+    msg: &Message,
+    keypair: &Keypair,
+    sighash_type: EcdsaSighashType,
+    nonce: EcdsaNonce,
+) -> bitcoin::ecdsa::Signature {
+    let signature = match nonce {
+        EcdsaNonce::Rfc6979 => secp.sign_ecdsa(msg, &keypair.secret_key()),
+        EcdsaNonce::LowR => secp.sign_ecdsa_low_r(msg, &keypair.secret_key()),
+    };
+    info!(
+        "Generated ECDSA signature for message with {:?} nonce",
+        nonce
+    );
     bitcoin::ecdsa::Signature {
         signature,
-        sighash_type: EcdsaSighashType::All,
+        sighash_type,
+    }
+}
+
+/// Human-readable report of `tx` for debugging: each input's outpoint, source address
+/// and value, each output's value and destination, the totals and the fee.
+///
+/// `prevouts` are matched to inputs by position. Inputs without one are shown as
+/// unknown, and the totals and fee are then omitted.
+pub fn explain_tx(tx: &Transaction, prevouts: &[TxOut], network: Network) -> String {
+    let describe = |script: &Script| match Address::from_script(script, network) {
+        Ok(address) => address.to_string(),
+        Err(_) if script.is_op_return() => format!("OP_RETURN {}", script.to_hex_string()),
+        Err(_) => format!("script {}", script.to_hex_string()),
+    };
+
+    let mut report = format!(
+        "Transaction {} (version {}, locktime {}, {} vB)\n",
+        tx.compute_txid(),
+        tx.version.0,
+        tx.lock_time,
+        tx.vsize()
+    );
+    report.push_str("Inputs:\n");
+    for (i, input) in tx.input.iter().enumerate() {
+        match prevouts.get(i) {
+            Some(prevout) => report.push_str(&format!(
+                "  {}: {} from {} ({} sat)\n",
+                i,
+                input.previous_output,
+                describe(&prevout.script_pubkey),
+                prevout.value.to_sat()
+            )),
+            None => report.push_str(&format!(
+                "  {}: {} from unknown prevout\n",
+                i, input.previous_output
+            )),
+        }
+    }
+    report.push_str("Outputs:\n");
+    for (i, output) in tx.output.iter().enumerate() {
+        report.push_str(&format!(
+            "  {}: {} sat to {}\n",
+            i,
+            output.value.to_sat(),
+            describe(&output.script_pubkey)
+        ));
+    }
+
+    let total_out: Amount = tx.output.iter().map(|output| output.value).sum();
+    if prevouts.len() >= tx.input.len() {
+        let total_in: Amount = prevouts[..tx.input.len()]
+            .iter()
+            .map(|prevout| prevout.value)
+            .sum();
+        let fee = total_in.checked_sub(total_out);
+        report.push_str(&format!(
+            "Total in: {} sat, total out: {} sat, fee: {}",
+            total_in.to_sat(),
+            total_out.to_sat(),
+            fee.map_or("negative".to_string(), |fee| format!(
+                "{} sat",
+                fee.to_sat()
+            ))
+        ));
+    } else {
+        report.push_str(&format!("Total out: {} sat", total_out.to_sat()));
+    }
+    report
+}
+
+/// Record of what a signed transaction commits to, for audit logs.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SigningTrace {
+    /// `(sighash_hex, signature_hex, pubkey_hex)` for each signature in input order. An
+    /// input signed by several keys, like the instant refund, has one entry per signature.
+    pub per_input: Vec<(String, String, String)>,
+}
+
+/// Verifies every input of `tx` against its prevout.
+///
+/// Supports P2TR key-path spends and tapscript spends built from the opcodes used by the
+/// HTLC leaves (hashlocks, timelocks, OP_CHECKSIG/OP_CHECKSIGADD). Any other script fails
+/// verification rather than being accepted.
+pub fn verify_tx(tx: &Transaction, prevouts: &[TxOut]) -> Result<(), TxUtilsError> {
+    signing_trace(tx, prevouts).map(|_| ())
+}
+
+/// Verifies `tx` like [`verify_tx`] and returns the sighash, signature and public key of
+/// every signature checked, so the output of any spend builder can be audited.
+pub fn signing_trace(tx: &Transaction, prevouts: &[TxOut]) -> Result<SigningTrace, TxUtilsError> {
+    if prevouts.len() != tx.input.len() {
+        return Err(TxUtilsError::ScriptVerificationFailed {
+            index: 0,
+            reason: format!(
+                "{} prevouts supplied for {} inputs",
+                prevouts.len(),
+                tx.input.len()
+            ),
+        });
+    }
+    let secp = Secp256k1::verification_only();
+    let mut sighash_cache = SighashCache::new(tx);
+    let mut trace = SigningTrace::default();
+    for index in 0..tx.input.len() {
+        verify_input(&secp, &mut sighash_cache, tx, index, prevouts, &mut trace).map_err(
+            |reason| {
+                error!("Input {} failed verification: {}", index, reason);
+                TxUtilsError::ScriptVerificationFailed { index, reason }
+            },
+        )?;
+    }
+    info!("Verified all {} inputs", tx.input.len());
+    Ok(trace)
+}
+
+fn verify_input(
+    secp: &Secp256k1<bitcoin::secp256k1::VerifyOnly>,
+    sighash_cache: &mut SighashCache<&Transaction>,
+    tx: &Transaction,
+    index: usize,
+    prevouts: &[TxOut],
+    trace: &mut SigningTrace,
+) -> Result<(), String> {
+    let script_pubkey = &prevouts[index].script_pubkey;
+    if !script_pubkey.is_p2tr() {
+        return Err("prevout is not a P2TR output".to_string());
+    }
+    let output_key = XOnlyPublicKey::from_slice(&script_pubkey.as_bytes()[2..])
+        .map_err(|e| format!("invalid output key: {}", e))?;
+
+    let mut stack: Vec<Vec<u8>> = tx.input[index].witness.iter().map(|e| e.to_vec()).collect();
+    let annex = if stack.len() >= 2
+        && stack
+            .last()
+            .is_some_and(|e| e.first() == Some(&TAPROOT_ANNEX_PREFIX))
+    {
+        stack.pop()
+    } else {
+        None
+    };
+    let annex = annex
+        .as_deref()
+        .map(Annex::new)
+        .transpose()
+        .map_err(|e| format!("invalid annex: {}", e))?;
+
+    match stack.len() {
+        0 => Err("empty witness".to_string()),
+        1 => {
+            let (signature, sighash_type) = parse_schnorr_signature(&stack[0])?;
+            let sighash = sighash_cache
+                .taproot_signature_hash(
+                    index,
+                    &Prevouts::All(prevouts),
+                    annex.clone(),
+                    None,
+                    sighash_type,
+                )
+                .map_err(|e| e.to_string())?;
+            let msg = Message::from_digest(sighash.to_byte_array());
+            secp.verify_schnorr(&signature, &msg, &output_key)
+                .map_err(|e| format!("invalid key-path signature: {}", e))?;
+            record_signature(trace, &msg, &stack[0], &output_key);
+            Ok(())
+        }
+        _ => {
+            let control_block = ControlBlock::decode(&stack.pop().unwrap_or_default())
+                .map_err(|e| format!("invalid control block: {}", e))?;
+            let script = ScriptBuf::from_bytes(stack.pop().unwrap_or_default());
+            if control_block.leaf_version != LeafVersion::TapScript {
+                return Err("unsupported leaf version".to_string());
+            }
+            if !control_block.verify_taproot_commitment(secp, output_key, &script) {
+                return Err("control block does not commit to the script".to_string());
+            }
+            let leaf_hash = TapLeafHash::from_script(&script, LeafVersion::TapScript);
+            let mut check_signature =
+                |signature_bytes: &[u8], pubkey: &[u8]| -> Result<(), String> {
+                    let pubkey = XOnlyPublicKey::from_slice(pubkey)
+                        .map_err(|e| format!("invalid public key: {}", e))?;
+                    let (signature, sighash_type) = parse_schnorr_signature(signature_bytes)?;
+                    let sighash = sighash_cache
+                        .taproot_signature_hash(
+                            index,
+                            &Prevouts::All(prevouts),
+                            annex.clone(),
+                            Some((leaf_hash, NO_CODESEPARATOR)),
+                            sighash_type,
+                        )
+                        .map_err(|e| e.to_string())?;
+                    let msg = Message::from_digest(sighash.to_byte_array());
+                    secp.verify_schnorr(&signature, &msg, &pubkey)
+                        .map_err(|e| format!("invalid signature for {}: {}", pubkey, e))?;
+                    record_signature(trace, &msg, signature_bytes, &pubkey);
+                    Ok(())
+                };
+            execute_tapscript(&script, stack, tx, index, &mut check_signature)
+        }
+    }
+}
+
+fn record_signature(
+    trace: &mut SigningTrace,
+    msg: &Message,
+    signature: &[u8],
+    pubkey: &XOnlyPublicKey,
+) {
+    trace.per_input.push((
+        hex::encode(msg.as_ref()),
+        hex::encode(signature),
+        pubkey.to_string(),
+    ));
+}
+
+fn parse_schnorr_signature(bytes: &[u8]) -> Result<(schnorr::Signature, TapSighashType), String> {
+    let (signature, sighash_type) = match bytes.len() {
+        64 => (bytes, TapSighashType::Default),
+        65 => {
+            let sighash_type = TapSighashType::from_consensus_u8(bytes[64])
+                .map_err(|e| format!("invalid sighash type: {}", e))?;
+            if sighash_type == TapSighashType::Default {
+                return Err("explicit default sighash byte".to_string());
+            }
+            (&bytes[..64], sighash_type)
+        }
+        len => return Err(format!("invalid signature length {}", len)),
+    };
+    let signature = schnorr::Signature::from_slice(signature)
+        .map_err(|e| format!("invalid signature: {}", e))?;
+    Ok((signature, sighash_type))
+}
+
+/// Runs a tapscript over `stack`. A successful script leaves exactly one true element.
+fn execute_tapscript<F>(
+    script: &Script,
+    mut stack: Vec<Vec<u8>>,
+    tx: &Transaction,
+    index: usize,
+    check_signature: &mut F,
+) -> Result<(), String>
+where
+    F: FnMut(&[u8], &[u8]) -> Result<(), String>,
+{
+    fn pop(stack: &mut Vec<Vec<u8>>) -> Result<Vec<u8>, String> {
+        stack.pop().ok_or_else(|| "stack underflow".to_string())
+    }
+
+    for instruction in script.instructions() {
+        let opcode = match instruction.map_err(|e| format!("invalid script: {}", e))? {
+            Instruction::PushBytes(bytes) => {
+                stack.push(bytes.as_bytes().to_vec());
+                continue;
+            }
+            Instruction::Op(opcode) => opcode,
+        };
+        let code = opcode.to_u8();
+        if (opcodes::all::OP_PUSHNUM_1.to_u8()..=opcodes::all::OP_PUSHNUM_16.to_u8())
+            .contains(&code)
+        {
+            let value = (code - opcodes::all::OP_PUSHNUM_1.to_u8() + 1) as i64;
+            stack.push(encode_script_num(value));
+            continue;
+        }
+        match opcode {
+            opcodes::all::OP_PUSHNUM_NEG1 => stack.push(encode_script_num(-1)),
+            opcodes::all::OP_DROP => {
+                pop(&mut stack)?;
+            }
+            opcodes::all::OP_2DROP => {
+                pop(&mut stack)?;
+                pop(&mut stack)?;
+            }
+            opcodes::all::OP_SHA256 => {
+                let item = pop(&mut stack)?;
+                stack.push(sha256::Hash::hash(&item).to_byte_array().to_vec());
+            }
+            opcodes::all::OP_HASH256 => {
+                let item = pop(&mut stack)?;
+                stack.push(sha256d::Hash::hash(&item).to_byte_array().to_vec());
+            }
+            opcodes::all::OP_EQUAL | opcodes::all::OP_EQUALVERIFY => {
+                let a = pop(&mut stack)?;
+                let b = pop(&mut stack)?;
+                push_or_verify(&mut stack, opcode, opcodes::all::OP_EQUALVERIFY, a == b)?;
+            }
+            opcodes::all::OP_NUMEQUAL | opcodes::all::OP_NUMEQUALVERIFY => {
+                let a = decode_script_num(&pop(&mut stack)?, 4)?;
+                let b = decode_script_num(&pop(&mut stack)?, 4)?;
+                push_or_verify(&mut stack, opcode, opcodes::all::OP_NUMEQUALVERIFY, a == b)?;
+            }
+            opcodes::all::OP_VERIFY => {
+                if !cast_to_bool(&pop(&mut stack)?) {
+                    return Err("OP_VERIFY failed".to_string());
+                }
+            }
+            opcodes::all::OP_CHECKSIG | opcodes::all::OP_CHECKSIGVERIFY => {
+                let pubkey = pop(&mut stack)?;
+                let signature = pop(&mut stack)?;
+                // An empty signature is a valid "no" vote, any other failure is fatal
+                let success = !signature.is_empty();
+                if success {
+                    check_signature(&signature, &pubkey)?;
+                }
+                push_or_verify(&mut stack, opcode, opcodes::all::OP_CHECKSIGVERIFY, success)?;
+            }
+            opcodes::all::OP_CHECKSIGADD => {
+                let pubkey = pop(&mut stack)?;
+                let count = decode_script_num(&pop(&mut stack)?, 4)?;
+                let signature = pop(&mut stack)?;
+                if !signature.is_empty() {
+                    check_signature(&signature, &pubkey)?;
+                }
+                stack.push(encode_script_num(count + !signature.is_empty() as i64));
+            }
+            opcodes::all::OP_CSV => {
+                let operand = decode_script_num(stack.last().ok_or("stack underflow")?, 5)?;
+                check_sequence(tx, index, operand)?;
+            }
+            opcodes::all::OP_CLTV => {
+                let operand = decode_script_num(stack.last().ok_or("stack underflow")?, 5)?;
+                check_lock_time(tx, index, operand)?;
+            }
+            _ => return Err(format!("unsupported opcode {}", opcode)),
+        }
+    }
+
+    match stack.as_slice() {
+        [top] if cast_to_bool(top) => Ok(()),
+        [_] => Err("script evaluated to false".to_string()),
+        _ => Err(format!("{} stack items left, expected 1", stack.len())),
+    }
+}
+
+fn push_or_verify(
+    stack: &mut Vec<Vec<u8>>,
+    opcode: Opcode,
+    verify_opcode: Opcode,
+    success: bool,
+) -> Result<(), String> {
+    if opcode == verify_opcode {
+        if !success {
+            return Err(format!("{} failed", opcode));
+        }
+    } else {
+        stack.push(encode_script_num(success as i64));
+    }
+    Ok(())
+}
+
+/// BIP-112 check of a CSV operand against the input's nSequence.
+fn check_sequence(tx: &Transaction, index: usize, operand: i64) -> Result<(), String> {
+    const DISABLE_FLAG: i64 = 1 << 31;
+    const TYPE_FLAG: u32 = 1 << 22;
+    const VALUE_MASK: u32 = 0x0000_ffff;
+    if operand < 0 {
+        return Err("negative OP_CSV operand".to_string());
     }
-}
\ No newline at end of file
+    if operand & DISABLE_FLAG != 0 {
+        return Ok(());
+    }
+    let sequence = tx.input[index].sequence.to_consensus_u32();
+    if tx.version.0 < 2 || sequence & (1 << 31) != 0 {
+        return Err("input sequence does not enable relative locktime".to_string());
+    }
+    let operand = operand as u32;
+    if operand & TYPE_FLAG != sequence & TYPE_FLAG {
+        return Err("OP_CSV unit does not match input sequence".to_string());
+    }
+    if operand & VALUE_MASK > sequence & VALUE_MASK {
+        return Err(format!(
+            "relative locktime {} not satisfied by sequence {}",
+            operand & VALUE_MASK,
+            sequence & VALUE_MASK
+        ));
+    }
+    Ok(())
+}
+
+/// BIP-65 check of a CLTV operand against the transaction's nLockTime.
+fn check_lock_time(tx: &Transaction, index: usize, operand: i64) -> Result<(), String> {
+    const LOCK_TIME_THRESHOLD: i64 = 500_000_000;
+    if operand < 0 {
+        return Err("negative OP_CLTV operand".to_string());
+    }
+    let lock_time = tx.lock_time.to_consensus_u32() as i64;
+    if (operand < LOCK_TIME_THRESHOLD) != (lock_time < LOCK_TIME_THRESHOLD) {
+        return Err("OP_CLTV unit does not match transaction locktime".to_string());
+    }
+    if operand > lock_time {
+        return Err(format!(
+            "absolute locktime {} not satisfied by {}",
+            operand, lock_time
+        ));
+    }
+    if tx.input[index].sequence == Sequence::MAX {
+        return Err("input sequence disables locktime".to_string());
+    }
+    Ok(())
+}
+
+fn cast_to_bool(bytes: &[u8]) -> bool {
+    match bytes.split_last() {
+        None => false,
+        Some((last, rest)) => rest.iter().any(|b| *b != 0) || (*last != 0 && *last != 0x80),
+    }
+}
+
+fn encode_script_num(value: i64) -> Vec<u8> {
+    if value == 0 {
+        return Vec::new();
+    }
+    let negative = value < 0;
+    let mut abs = value.unsigned_abs();
+    let mut bytes = Vec::new();
+    while abs > 0 {
+        bytes.push((abs & 0xff) as u8);
+        abs >>= 8;
+    }
+    if bytes.last().is_some_and(|b| b & 0x80 != 0) {
+        bytes.push(if negative { 0x80 } else { 0x00 });
+    } else if negative {
+        *bytes.last_mut().unwrap() |= 0x80;
+    }
+    bytes
+}
+
+fn decode_script_num(bytes: &[u8], max_len: usize) -> Result<i64, String> {
+    if bytes.len() > max_len {
+        return Err(format!("script number longer than {} bytes", max_len));
+    }
+    if encode_script_num(decode_unchecked(bytes)) != bytes {
+        return Err("non-minimal script number".to_string());
+    }
+    Ok(decode_unchecked(bytes))
+}
+
+fn decode_unchecked(bytes: &[u8]) -> i64 {
+    let Some((last, _)) = bytes.split_last() else {
+        return 0;
+    };
+    let mut value = bytes
+        .iter()
+        .enumerate()
+        .fold(0i64, |acc, (i, b)| acc | ((*b as i64) << (8 * i)));
+    if last & 0x80 != 0 {
+        value &= !(0x80i64 << (8 * (bytes.len() - 1)));
+        value = -value;
+    }
+    value
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{
+        INITIATOR_PRIVATE_KEY, PREIMAGE, RESPONDER_PRIVATE_KEY, mock_bitcoin, mock_htlc_utxo,
+    };
+
+    // BIP32 test vector 1 (seed 000102030405060708090a0b0c0d0e0f)
+    const TEST_XPRV: &str = "xprv9s21ZrQH143K3QTDL4LXw2F7HEK3wJUD2nW2nRk4stbPy6cq3jPPqjiChkVvvNKmPGJxWUtg6LnF5kejMRNNU3TGtRBeJgk33yuGBxrMPHi";
+
+    #[test]
+    fn test_derive_keypair_from_xprv() {
+        let keypair =
+            derive_keypair_from_xprv(TEST_XPRV, "m/0'").expect("Expected valid derivation");
+        assert_eq!(
+            keypair.secret_key().display_secret().to_string(),
+            "edb2e14f9ee77d26dd93b4ecede8d16ed408ce149b6cd80b0715a2d911a0afea"
+        );
+
+        let result = derive_keypair_from_xprv(TEST_XPRV, "m/not/a/path");
+        assert!(matches!(
+            result,
+            Err(TxUtilsError::InvalidDerivationPath(_))
+        ));
+
+        let result = derive_keypair_from_xprv("xprv-invalid", "m/0'");
+        assert!(matches!(
+            result,
+            Err(TxUtilsError::InvalidExtendedPrivateKey(_))
+        ));
+    }
+
+    #[test]
+    fn test_derive_keypair_from_wif() {
+        let (keypair, network) =
+            derive_keypair_from_wif("cUKjgDaHBYDxPYXpCajN6Eh4F3Vw9bFvqcYXTj4BxVxgim8jmLcs")
+                .expect("Expected a valid testnet WIF");
+        assert_eq!(network, NetworkKind::Test);
+        assert_eq!(
+            keypair.x_only_public_key().0.to_string(),
+            mock_bitcoin().initiator_pubkey
+        );
+        assert_eq!(
+            keypair.secret_key().display_secret().to_string(),
+            INITIATOR_PRIVATE_KEY
+        );
+
+        let result =
+            derive_keypair_from_wif("cUKjgDaHBYDxPYXpCajN6Eh4F3Vw9bFvqcYXTj4BxVxgim8jmLct");
+        assert!(matches!(result, Err(TxUtilsError::InvalidWif(_))));
+    }
+
+    #[test]
+    fn test_script_num_round_trip() {
+        for value in [
+            0i64, 1, -1, 127, 128, -128, 255, 256, 144, 4_194_473, 2_400_000,
+        ] {
+            let encoded = encode_script_num(value);
+            assert_eq!(decode_script_num(&encoded, 5), Ok(value));
+        }
+        assert_eq!(encode_script_num(128), vec![0x80, 0x00]);
+        assert!(decode_script_num(&[0x01, 0x00], 4).is_err());
+        assert!(decode_script_num(&[0x01, 0x02, 0x03, 0x04, 0x05], 4).is_err());
+    }
+
+    #[test]
+    fn test_taproot_sighash_commits_to_annex() {
+        let tx = build_transaction(
+            vec![build_input(OutPoint::null(), None, None)],
+            vec![TxOut {
+                value: Amount::from_sat(900),
+                script_pubkey: ScriptBuf::new_op_return([]),
+            }],
+        );
+        let prevouts = vec![TxOut {
+            value: Amount::from_sat(1000),
+            script_pubkey: ScriptBuf::new_op_return([]),
+        }];
+        let leaf_hash = TapLeafHash::from_script(&ScriptBuf::new(), LeafVersion::TapScript);
+
+        let without =
+            compute_taproot_sighash(&tx, 0, &prevouts, leaf_hash, TapSighashType::Default, None)
+                .unwrap();
+        let annex = [TAPROOT_ANNEX_PREFIX, 0x01, 0x02];
+        let with = compute_taproot_sighash(
+            &tx,
+            0,
+            &prevouts,
+            leaf_hash,
+            TapSighashType::Default,
+            Some(&annex),
+        )
+        .unwrap();
+        assert_ne!(without, with);
+
+        // An annex must start with the 0x50 prefix
+        let result = compute_taproot_sighash(
+            &tx,
+            0,
+            &prevouts,
+            leaf_hash,
+            TapSighashType::Default,
+            Some(&[0x01]),
+        );
+        assert!(matches!(
+            result,
+            Err(TxUtilsError::SighashComputationError(_))
+        ));
+    }
+
+    #[test]
+    fn test_taproot_key_sighash_differs_from_script_path() {
+        let tx = build_transaction(
+            vec![build_input(OutPoint::null(), None, None)],
+            vec![TxOut {
+                value: Amount::from_sat(900),
+                script_pubkey: ScriptBuf::new_op_return([]),
+            }],
+        );
+        let prevouts = vec![TxOut {
+            value: Amount::from_sat(1000),
+            script_pubkey: ScriptBuf::new_op_return([]),
+        }];
+        let leaf_hash = TapLeafHash::from_script(&ScriptBuf::new(), LeafVersion::TapScript);
+
+        let key_path =
+            compute_taproot_key_sighash(&tx, 0, &prevouts, TapSighashType::Default).unwrap();
+        let script_path =
+            compute_taproot_sighash(&tx, 0, &prevouts, leaf_hash, TapSighashType::Default, None)
+                .unwrap();
+        assert_ne!(key_path, script_path);
+    }
+
+    #[test]
+    fn test_build_transaction_with_version() {
+        let input = || vec![build_input(OutPoint::null(), None, None)];
+        assert_eq!(build_transaction(input(), vec![]).version, Version::TWO);
+        let tx = build_transaction_with_version(input(), vec![], Some(Version(3)));
+        assert_eq!(tx.version, Version(3));
+    }
+
+    #[test]
+    fn test_build_input_sequence_override() {
+        let outpoint = OutPoint::null();
+        assert_eq!(
+            build_input(outpoint, None, None).sequence,
+            Sequence::ENABLE_RBF_NO_LOCKTIME
+        );
+        assert_eq!(
+            build_input(outpoint, Some(144), None).sequence,
+            Sequence::from_height(144)
+        );
+
+        let custom = Sequence::from_consensus(0xfffffffd);
+        assert_eq!(build_input(outpoint, None, Some(custom)).sequence, custom);
+        assert_eq!(
+            build_input(outpoint, Some(144), Some(custom)).sequence,
+            custom
+        );
+    }
+
+    #[test]
+    fn test_dust_policy() {
+        let script_pubkey = ScriptBuf::new_p2wpkh(&bitcoin::WPubkeyHash::all_zeros());
+        let output = |sats| TxOut {
+            value: Amount::from_sat(sats),
+            script_pubkey: script_pubkey.clone(),
+        };
+
+        // P2WPKH dust limit at 3 sat/vB is 294 sat
+        assert!(is_dust(&output(293), DustPolicy::Standard));
+        assert!(!is_dust(&output(294), DustPolicy::Standard));
+        assert!(is_dust(&output(293), DustPolicy::default()));
+
+        let custom = DustPolicy::Threshold(Amount::from_sat(100));
+        assert!(!is_dust(&output(150), custom));
+        assert!(is_dust(&output(99), custom));
+
+        let low_relay_fee = DustPolicy::RelayFeeRate(FeeRate::from_sat_per_vb_u32(1));
+        assert!(!is_dust(&output(98), low_relay_fee));
+        assert!(is_dust(&output(97), low_relay_fee));
+    }
+
+    #[test]
+    fn test_effective_value() {
+        // Key-path P2TR input: 41 non-witness bytes and a 66-byte witness, 57.5 vB
+        let input_weight = Weight::from_wu(41 * 4 + 66);
+        let value = Amount::from_sat(1_000);
+
+        let low = FeeRate::from_sat_per_vb_u32(2);
+        assert_eq!(effective_value(value, input_weight, low), 1_000 - 115);
+
+        // Above dust, yet worth less than it costs to spend at 20 sat/vB
+        let high = FeeRate::from_sat_per_vb_u32(20);
+        assert_eq!(effective_value(value, input_weight, high), 1_000 - 1_150);
+        assert!(effective_value(value, input_weight, high) < 0);
+        assert_eq!(
+            effective_value(Amount::ZERO, input_weight, FeeRate::ZERO),
+            0
+        );
+    }
+
+    #[test]
+    fn test_p2wsh_sign_single_anyonecanpay() {
+        let secp = new_secp_context();
+        let keypair =
+            derive_keypair("250bd3a0f83f249fcb9298b1a89458453f8b6301c3076d6f48f22a25d40899d3")
+                .unwrap();
+        let pubkey = bitcoin::PublicKey::new(keypair.public_key());
+        let witness_script = ScriptBuf::builder()
+            .push_key(&pubkey)
+            .push_opcode(opcodes::all::OP_CHECKSIG)
+            .into_script();
+        let prevout = TxOut {
+            value: Amount::from_sat(10_000),
+            script_pubkey: ScriptBuf::new_p2wsh(&witness_script.wscript_hash()),
+        };
+        let output = |sats| TxOut {
+            value: Amount::from_sat(sats),
+            script_pubkey: ScriptBuf::new_op_return([]),
+        };
+        let mut tx = build_transaction(
+            vec![
+                build_input(OutPoint::null(), None, None),
+                build_input(OutPoint::new(bitcoin::Txid::all_zeros(), 1), None, None),
+            ],
+            vec![output(9_000), output(8_000)],
+        );
+        let prevouts = vec![prevout.clone(), prevout];
+        let sighash_type = EcdsaSighashType::SinglePlusAnyoneCanPay;
+
+        let sighash = compute_sighash(&tx, 1, &prevouts, &witness_script, sighash_type).unwrap();
+        let msg = Message::from_digest(sighash);
+        let signature = sign_ecdsa(&secp, &msg, &keypair, sighash_type);
+        assert_eq!(signature.sighash_type, sighash_type);
+        assert_eq!(*signature.to_vec().last().unwrap(), 0x83);
+        secp.verify_ecdsa(&msg, &signature.signature, &keypair.public_key())
+            .expect("Expected a valid signature");
+
+        // Other inputs and outputs are not committed to
+        tx.input[0].previous_output.vout = 7;
+        tx.output[0].value = Amount::from_sat(1);
+        assert_eq!(
+            compute_sighash(&tx, 1, &prevouts, &witness_script, sighash_type).unwrap(),
+            sighash
+        );
+        assert_ne!(
+            compute_sighash(&tx, 1, &prevouts, &witness_script, EcdsaSighashType::All).unwrap(),
+            sighash
+        );
+    }
+
+    #[test]
+    fn test_sign_ecdsa_low_r() {
+        let secp = new_secp_context();
+        let keypair =
+            derive_keypair("c929c768be0902d5bb7ae6e38bdc6b3b24cefbe93650da91975756a09e408460")
+                .unwrap();
+        let mut high_r_seen = false;
+        for i in 0..32u8 {
+            let msg = Message::from_digest(sha256::Hash::hash(&[i]).to_byte_array());
+            let low_r = sign_ecdsa_with_nonce(
+                &secp,
+                &msg,
+                &keypair,
+                EcdsaSighashType::All,
+                EcdsaNonce::LowR,
+            );
+            assert!(low_r.signature.serialize_compact()[0] < 0x80);
+            assert!(low_r.to_vec().len() <= 71);
+            secp.verify_ecdsa(&msg, &low_r.signature, &keypair.public_key())
+                .expect("Expected a valid low-R signature");
+
+            let default = sign_ecdsa(&secp, &msg, &keypair, EcdsaSighashType::All);
+            assert!(default.to_vec().len() <= 72);
+            high_r_seen |= default.signature.serialize_compact()[0] >= 0x80;
+        }
+        // Without grinding about half the nonces have a high R, costing a byte each
+        assert!(high_r_seen);
+    }
+
+    #[test]
+    fn test_explain_redeem_tx() {
+        let bitcoin = mock_bitcoin();
+        let (htlc_address, _) =
+            crate::p2tr::generate_p2tr_address(&bitcoin, bitcoin::KnownHrp::Testnets).unwrap();
+        let transfer_to_address = Address::from_str("tb1qleejf8n05j660f74q69pwhvyg8n620xz8r60h2")
+            .unwrap()
+            .assume_checked();
+        let utxo = mock_htlc_utxo(10_000);
+        let tx = crate::p2tr::redeem_taproot_htlc(
+            &bitcoin,
+            PREIMAGE,
+            RESPONDER_PRIVATE_KEY,
+            vec![utxo],
+            &transfer_to_address,
+            3,
+            bitcoin::KnownHrp::Testnets,
+        )
+        .unwrap();
+        let prevouts = vec![TxOut {
+            value: Amount::from_sat(10_000),
+            script_pubkey: htlc_address.script_pubkey(),
+        }];
+
+        let report = explain_tx(&tx, &prevouts, Network::Testnet);
+        let fee = 10_000 - tx.output[0].value.to_sat();
+        assert!(report.contains(&format!(
+            "9c8a5fc42f8f57537f3a2746be78632f6ff2f0bb65a87c196496db50970c5787:0 from {}",
+            htlc_address
+        )));
+        assert!(report.contains(&format!("to {}", transfer_to_address)));
+        assert!(report.ends_with(&format!("fee: {} sat", fee)));
+
+        assert!(explain_tx(&tx, &[], Network::Testnet).contains("from unknown prevout"));
+    }
+}
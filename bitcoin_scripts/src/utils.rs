@@ -1,24 +1,21 @@
 
-use bitcoin::Address;
+use crate::amount::sats_to_btc_string;
+use crate::error::UtilsError;
+use bitcoin::{Address, Network, Psbt, Transaction, Txid};
 use log::{error, info};
 use reqwest::Client;
 use serde::{Deserialize, Serialize};
-use thiserror::Error;
-
-#[derive(Error, Debug)]
-pub enum UtilsError {
-    #[error("HTTP request failed: {0}")]
-    HttpRequestError(String),
-    #[error("Failed to parse response: {0}")]
-    ParseError(String),
-    #[error("Broadcast failed with status {status}: {message}")]
-    BroadcastError {
-        status: reqwest::StatusCode,
-        message: String,
-    },
-}
-
-#[derive(Debug, Serialize, Deserialize)]
+use std::collections::{BTreeMap, HashMap};
+use std::future::Future;
+use std::time::Duration;
+
+/// Confirmed transactions returned per page by esplora's `/address/{addr}/txs[/chain]`.
+const ESPLORA_CHAIN_PAGE_SIZE: usize = 25;
+
+/// Delay used when a 429 response carries no usable `Retry-After` header.
+const DEFAULT_RETRY_AFTER: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct UtxoStatus {
     pub confirmed: bool,
     pub block_height: u32,
@@ -26,7 +23,7 @@ pub struct UtxoStatus {
     pub block_time: u64,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Utxo {
     pub txid: String,
     pub vout: u32,
@@ -34,27 +31,230 @@ pub struct Utxo {
     pub value: u64,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+/// Confirmation status of a transaction; unconfirmed transactions carry no block data.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TxRefStatus {
+    pub confirmed: bool,
+    pub block_height: Option<u32>,
+    pub block_hash: Option<String>,
+    pub block_time: Option<u64>,
+}
+
+/// A transaction touching an address, as listed by esplora.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TxRef {
+    pub txid: String,
+    pub status: TxRefStatus,
+}
+
+/// Response of `/v1/fees/recommended`, in sat/vB. Some mempool-compatible servers omit
+/// tiers, so every field is optional; see [`RecommendedFeeRate::rate_for`].
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
 pub struct RecommendedFeeRate {
-    pub fastest_fee: u64,
-    pub half_hour_fee: u64,
-    pub hour_fee: u64,
-    pub economy_fee: u64,
-    pub minimum_fee: u64,
+    pub fastest_fee: Option<u64>,
+    pub half_hour_fee: Option<u64>,
+    pub hour_fee: Option<u64>,
+    pub economy_fee: Option<u64>,
+    pub minimum_fee: Option<u64>,
+}
+
+/// A block projected from the current mempool, as served by mempool.space's
+/// `/api/v1/fees/mempool-blocks`. Fees are in sat/vB.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MempoolBlock {
+    pub block_size: u64,
+    pub block_v_size: f64,
+    pub n_tx: u64,
+    pub total_fees: u64,
+    pub median_fee: f64,
+    /// Fee rates from the block's cheapest to its most expensive transactions.
+    pub fee_range: Vec<f64>,
+}
+
+/// Rate in sat/vB, rounded up, to land in the `block`-th projected block (1 is the next
+/// block): that block's median fee. Returns `None` if fewer blocks are projected.
+pub fn rate_to_enter_block(blocks: &[MempoolBlock], block: usize) -> Option<u64> {
+    block
+        .checked_sub(1)
+        .and_then(|index| blocks.get(index))
+        .map(|projected| projected.median_fee.ceil() as u64)
+}
+
+/// Confirmation urgency, selecting one of the recommended fee rates.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum FeeTier {
+    Fastest,
+    HalfHour,
+    Hour,
+    Economy,
+    Minimum,
+}
+
+impl RecommendedFeeRate {
+    /// Fee rate in sat/vB for the given tier. A missing tier falls back to the next
+    /// faster one (minimum → economy → hour → half hour → fastest), so a partial
+    /// response never underpays. Returns `None` only if no such tier is present.
+    pub fn rate_for(&self, tier: FeeTier) -> Option<u64> {
+        let tiers = [
+            self.minimum_fee,
+            self.economy_fee,
+            self.hour_fee,
+            self.half_hour_fee,
+            self.fastest_fee,
+        ];
+        let start = match tier {
+            FeeTier::Minimum => 0,
+            FeeTier::Economy => 1,
+            FeeTier::Hour => 2,
+            FeeTier::HalfHour => 3,
+            FeeTier::Fastest => 4,
+        };
+        tiers[start..].iter().find_map(|rate| *rate)
+    }
+}
+
+/// Lists the inputs of `psbt` as UTXOs, taking each value from `witness_utxo` or, if
+/// absent, from the matching output of `non_witness_utxo`. Inputs with neither are
+/// skipped. A PSBT carries no confirmation data, so every UTXO is marked unconfirmed.
+pub fn utxos_from_psbt(psbt: &Psbt) -> Vec<Utxo> {
+    psbt.unsigned_tx
+        .input
+        .iter()
+        .zip(&psbt.inputs)
+        .filter_map(|(txin, input)| {
+            let outpoint = txin.previous_output;
+            let prevout = input.witness_utxo.as_ref().or_else(|| {
+                input
+                    .non_witness_utxo
+                    .as_ref()
+                    .and_then(|tx| tx.output.get(outpoint.vout as usize))
+            });
+            let Some(prevout) = prevout else {
+                error!("PSBT input {} has no UTXO information, skipping", outpoint);
+                return None;
+            };
+            Some(Utxo {
+                txid: outpoint.txid.to_string(),
+                vout: outpoint.vout,
+                status: UtxoStatus {
+                    confirmed: false,
+                    block_height: 0,
+                    block_hash: String::new(),
+                    block_time: 0,
+                },
+                value: prevout.value.to_sat(),
+            })
+        })
+        .collect()
+}
+
+/// Joins an API base URL, which may include a subpath such as `/esplora/api`, with an
+/// endpoint path so that exactly one `/` separates them.
+pub fn join_url(base: &str, path: &str) -> String {
+    let base = base.trim_end_matches('/');
+    let path = path.trim_start_matches('/');
+    if path.is_empty() {
+        return base.to_string();
+    }
+    format!("{}/{}", base, path)
+}
+
+/// Public mempool.space esplora endpoint for `network`. Regtest (and any network
+/// mempool.space does not serve) maps to a local esplora instance on its default port.
+pub fn default_esplora_url(network: Network) -> &'static str {
+    match network {
+        Network::Bitcoin => "https://mempool.space/api",
+        Network::Testnet => "https://mempool.space/testnet/api",
+        Network::Testnet4 => "https://mempool.space/testnet4/api",
+        Network::Signet => "https://mempool.space/signet/api",
+        _ => "http://127.0.0.1:3002",
+    }
+}
+
+/// BIP21 URI requesting `amount_sats` to `address`, e.g.
+/// `bitcoin:tb1p...?amount=0.0005&label=Swap%201`, for display as a QR code.
+///
+/// The amount is in BTC without trailing zeros; the label is percent-encoded.
+pub fn payment_uri(address: &Address, amount_sats: u64, label: &str) -> String {
+    let amount = sats_to_btc_string(amount_sats);
+    let amount = amount.trim_end_matches('0').trim_end_matches('.');
+    let label: String = label
+        .bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                (b as char).to_string()
+            }
+            _ => format!("%{:02X}", b),
+        })
+        .collect();
+    format!("bitcoin:{}?amount={}&label={}", address, amount, label)
+}
+
+/// HTTP client for the esplora helpers. Gzip is enabled, so requests advertise
+/// `Accept-Encoding: gzip` and compressed responses, such as long UTXO lists, are
+/// decoded transparently. Setting that header by hand would disable the decoding.
+fn http_client() -> Client {
+    Client::builder().gzip(true).build().unwrap_or_default()
+}
+
+/// Maps a 429 response to `UtilsError::RateLimited`, honoring a `Retry-After` header in seconds.
+fn check_rate_limit(response: &reqwest::Response) -> Result<(), UtilsError> {
+    if response.status() != reqwest::StatusCode::TOO_MANY_REQUESTS {
+        return Ok(());
+    }
+    let retry_after = response
+        .headers()
+        .get(reqwest::header::RETRY_AFTER)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.trim().parse::<u64>().ok())
+        .map(Duration::from_secs)
+        .unwrap_or(DEFAULT_RETRY_AFTER);
+    error!(
+        "Rate limited by {}, retry after {:?}",
+        response.url(),
+        retry_after
+    );
+    Err(UtilsError::RateLimited { retry_after })
+}
+
+/// Runs `op` up to `max_attempts` times, sleeping for the server-provided delay
+/// whenever it is rate limited. Other errors are returned immediately.
+pub async fn with_retry<T, F, Fut>(max_attempts: u32, mut op: F) -> Result<T, UtilsError>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, UtilsError>>,
+{
+    let mut attempt = 1;
+    loop {
+        match op().await {
+            Err(UtilsError::RateLimited { retry_after }) if attempt < max_attempts => {
+                info!(
+                    "Attempt {}/{} rate limited, retrying after {:?}",
+                    attempt, max_attempts, retry_after
+                );
+                tokio::time::sleep(retry_after).await;
+                attempt += 1;
+            }
+            result => return result,
+        }
+    }
 }
 
 pub async fn fetch_utxos_for_address(
     rpc_url: &str,
     address: &Address,
 ) -> Result<Vec<Utxo>, UtilsError> {
-    let client = Client::new();
-    let url = format!("{}/address/{}/utxo", rpc_url.trim_end_matches('/'), address);
+    let client = http_client();
+    let url = join_url(rpc_url, &format!("address/{}/utxo", address));
     info!("Fetching UTXOs for address: {}", address);
 
     let response = client.get(&url).send().await.map_err(|e| {
         error!("Failed to fetch UTXOs for address {}: {}", address, e);
-        UtilsError::HttpRequestError(e.to_string())
+        UtilsError::HttpRequestError(e)
     })?;
+    check_rate_limit(&response)?;
 
     let utxos = response.json::<Vec<Utxo>>().await.map_err(|e| {
         error!(
@@ -68,9 +268,49 @@ pub async fn fetch_utxos_for_address(
     Ok(utxos)
 }
 
+/// Fetches the full transaction history of an address, newest first, following esplora's
+/// `/address/{addr}/txs/chain/{last_seen_txid}` pagination for confirmed transactions.
+pub async fn fetch_address_txs(rpc_url: &str, address: &Address) -> Result<Vec<TxRef>, UtilsError> {
+    let client = http_client();
+    let base = join_url(rpc_url, &format!("address/{}/txs", address));
+    info!("Fetching transaction history for address: {}", address);
+
+    // First page holds mempool transactions plus the newest confirmed ones
+    let first_page = fetch_tx_page(&client, &base).await?;
+    let mut confirmed_in_page = first_page.iter().filter(|tx| tx.status.confirmed).count();
+    let mut txs = first_page;
+
+    while confirmed_in_page == ESPLORA_CHAIN_PAGE_SIZE {
+        let last_seen = match txs.iter().rev().find(|tx| tx.status.confirmed) {
+            Some(tx) => tx.txid.clone(),
+            None => break,
+        };
+        let page =
+            fetch_tx_page(&client, &join_url(&base, &format!("chain/{}", last_seen))).await?;
+        confirmed_in_page = page.len();
+        txs.extend(page);
+    }
+
+    info!("Fetched {} transactions for address {}", txs.len(), address);
+    Ok(txs)
+}
+
+async fn fetch_tx_page(client: &Client, url: &str) -> Result<Vec<TxRef>, UtilsError> {
+    let response = client.get(url).send().await.map_err(|e| {
+        error!("Failed to fetch transaction page {}: {}", url, e);
+        UtilsError::HttpRequestError(e)
+    })?;
+    check_rate_limit(&response)?;
+
+    response.json::<Vec<TxRef>>().await.map_err(|e| {
+        error!("Failed to parse transaction page {}: {}", url, e);
+        UtilsError::ParseError(e.to_string())
+    })
+}
+
 pub async fn broadcast_trx(rpc_url: &str, trx_raw_hex: &str) -> Result<String, UtilsError> {
-    let client = Client::new();
-    let url = format!("{}/tx", rpc_url.trim_end_matches('/'));
+    let client = http_client();
+    let url = join_url(rpc_url, "tx");
     info!("Broadcasting transaction: {}", trx_raw_hex);
 
     let response = client
@@ -81,8 +321,9 @@ pub async fn broadcast_trx(rpc_url: &str, trx_raw_hex: &str) -> Result<String, U
         .await
         .map_err(|e| {
             error!("Failed to broadcast transaction: {}", e);
-            UtilsError::HttpRequestError(e.to_string())
+            UtilsError::HttpRequestError(e)
         })?;
+    check_rate_limit(&response)?;
 
     if response.status().is_success() {
         let txid = response.text().await.map_err(|e| {
@@ -113,16 +354,211 @@ pub async fn broadcast_trx(rpc_url: &str, trx_raw_hex: &str) -> Result<String, U
     }
 }
 
+/// Returns the fastest confirmation target (in blocks) whose estimated rate from esplora's
+/// `/fee-estimates` is met by `rate` (sat/vB). Returns `u16::MAX` if no target is met.
+pub fn estimated_blocks_for_rate(estimates: &BTreeMap<u16, f64>, rate: u64) -> u16 {
+    estimates
+        .iter()
+        .find(|(_, required_rate)| rate as f64 >= **required_rate)
+        .map(|(target, _)| *target)
+        .unwrap_or(u16::MAX)
+}
+
+/// Result of bitcoind's `submitpackage`, as relayed by esplora's `/txs/package`.
+#[derive(Debug, Deserialize)]
+struct PackageResult {
+    package_msg: String,
+    #[serde(rename = "tx-results", default)]
+    tx_results: HashMap<String, PackageTxResult>,
+}
+
+#[derive(Debug, Deserialize)]
+struct PackageTxResult {
+    #[serde(default)]
+    error: Option<String>,
+}
+
+/// Submits `txs_hex` (parents before children) as one package via esplora's
+/// `POST /txs/package`, so a child paying for its parent is not rejected for a missing
+/// input. Returns the txids in order, or `None` if the server has no package endpoint.
+pub async fn submit_package(
+    rpc_url: &str,
+    txs_hex: &[&str],
+) -> Result<Option<Vec<String>>, UtilsError> {
+    let mut txids = Vec::new();
+    for tx_hex in txs_hex {
+        let tx: Transaction =
+            bitcoin::consensus::encode::deserialize_hex(tx_hex.trim()).map_err(|e| {
+                error!("Failed to decode package transaction: {}", e);
+                UtilsError::ParseError(e.to_string())
+            })?;
+        txids.push(tx.compute_txid().to_string());
+    }
+
+    let client = http_client();
+    let url = join_url(rpc_url, "txs/package");
+    info!(
+        "Submitting package of {} transactions: {:?}",
+        txs_hex.len(),
+        txids
+    );
+    let response = client.post(&url).json(txs_hex).send().await.map_err(|e| {
+        error!("Failed to submit package: {}", e);
+        UtilsError::HttpRequestError(e)
+    })?;
+    check_rate_limit(&response)?;
+
+    let status = response.status();
+    if status == reqwest::StatusCode::NOT_FOUND || status == reqwest::StatusCode::METHOD_NOT_ALLOWED
+    {
+        info!("Server has no package endpoint (status {})", status);
+        return Ok(None);
+    }
+    if !status.is_success() {
+        let message = response
+            .text()
+            .await
+            .map_err(|e| UtilsError::ParseError(e.to_string()))?;
+        error!(
+            "Package submission failed with status {}: {}",
+            status, message
+        );
+        return Err(UtilsError::BroadcastError { status, message });
+    }
+
+    let result = response.json::<PackageResult>().await.map_err(|e| {
+        error!("Failed to parse package response: {}", e);
+        UtilsError::ParseError(e.to_string())
+    })?;
+    if result.package_msg != "success" {
+        let errors: Vec<String> = result
+            .tx_results
+            .into_iter()
+            .filter_map(|(wtxid, tx_result)| tx_result.error.map(|e| format!("{}: {}", wtxid, e)))
+            .collect();
+        let message = format!("{} {}", result.package_msg, errors.join(", "));
+        error!("Package rejected: {}", message);
+        return Err(UtilsError::BroadcastError { status, message });
+    }
+    info!("Package accepted: {:?}", txids);
+    Ok(Some(txids))
+}
+
+/// Rate in sat/vB, rounded up, for confirming within `target` blocks: the estimate for
+/// the largest target in `estimates` not above `target`. Returns `None` if every
+/// estimate is for a slower target.
+pub fn rate_for_target(estimates: &BTreeMap<u16, f64>, target: u16) -> Option<u64> {
+    estimates
+        .range(..=target)
+        .next_back()
+        .map(|(_, rate)| rate.ceil() as u64)
+}
+
+/// Fetches esplora's `/fee-estimates`, mapping confirmation targets in blocks to sat/vB.
+pub async fn fetch_fee_estimates(rpc_url: &str) -> Result<BTreeMap<u16, f64>, UtilsError> {
+    let client = http_client();
+    let url = join_url(rpc_url, "fee-estimates");
+    info!("Fetching fee estimates from: {}", url);
+
+    let response = client.get(&url).send().await.map_err(|e| {
+        error!("Failed to fetch fee estimates: {}", e);
+        UtilsError::HttpRequestError(e)
+    })?;
+    check_rate_limit(&response)?;
+
+    let estimates = response.json::<BTreeMap<u16, f64>>().await.map_err(|e| {
+        error!("Failed to parse fee estimates response: {}", e);
+        UtilsError::ParseError(e.to_string())
+    })?;
+
+    info!("Fetched {} fee estimates", estimates.len());
+    Ok(estimates)
+}
+
+/// Fetches a transaction by txid via esplora's `/tx/{txid}/hex`. Returns `None` when the
+/// server does not know the transaction.
+pub async fn fetch_transaction(
+    rpc_url: &str,
+    txid: &Txid,
+) -> Result<Option<Transaction>, UtilsError> {
+    let client = http_client();
+    let url = join_url(rpc_url, &format!("tx/{}/hex", txid));
+    info!("Fetching transaction: {}", txid);
+
+    let response = client.get(&url).send().await.map_err(|e| {
+        error!("Failed to fetch transaction {}: {}", txid, e);
+        UtilsError::HttpRequestError(e)
+    })?;
+    check_rate_limit(&response)?;
+
+    if response.status() == reqwest::StatusCode::NOT_FOUND {
+        info!("Transaction {} not found", txid);
+        return Ok(None);
+    }
+
+    let tx_hex = response.text().await.map_err(|e| {
+        error!("Failed to read transaction {}: {}", txid, e);
+        UtilsError::ParseError(e.to_string())
+    })?;
+    let tx = bitcoin::consensus::encode::deserialize_hex(tx_hex.trim()).map_err(|e| {
+        error!("Failed to decode transaction {}: {}", txid, e);
+        UtilsError::ParseError(e.to_string())
+    })?;
+    Ok(Some(tx))
+}
+
+/// Response of esplora's `/tx/{txid}/outspend/{vout}`; `txid` is set once spent.
+#[derive(Debug, Deserialize)]
+struct Outspend {
+    spent: bool,
+    txid: Option<String>,
+}
+
+/// Checks via esplora's `/tx/{txid}/outspend/{vout}` whether an output has been spent,
+/// returning the spending txid if so.
+pub async fn is_outpoint_spent(
+    rpc_url: &str,
+    txid: &Txid,
+    vout: u32,
+) -> Result<Option<Txid>, UtilsError> {
+    let client = http_client();
+    let url = join_url(rpc_url, &format!("tx/{}/outspend/{}", txid, vout));
+    info!("Checking whether {}:{} is spent", txid, vout);
+
+    let response = client.get(&url).send().await.map_err(|e| {
+        error!("Failed to fetch outspend of {}:{}: {}", txid, vout, e);
+        UtilsError::HttpRequestError(e)
+    })?;
+    check_rate_limit(&response)?;
+
+    let outspend = response.json::<Outspend>().await.map_err(|e| {
+        error!("Failed to parse outspend response: {}", e);
+        UtilsError::ParseError(e.to_string())
+    })?;
+    match (outspend.spent, outspend.txid) {
+        (false, _) => Ok(None),
+        (true, Some(spending_txid)) => spending_txid
+            .parse::<Txid>()
+            .map(Some)
+            .map_err(|e| UtilsError::ParseError(e.to_string())),
+        (true, None) => Err(UtilsError::ParseError(format!(
+            "outspend of {}:{} is spent but has no txid",
+            txid, vout
+        ))),
+    }
+}
+
 /// Fetches the current tip block height from the given RPC URL
 pub async fn fetch_tip_block_height(rpc_url: &str) -> Result<u32, UtilsError> {
-    let client = Client::new();
-    let url = format!("{}/blocks/tip/height", rpc_url.trim_end_matches('/'));
+    let client = http_client();
+    let url = join_url(rpc_url, "blocks/tip/height");
     info!("Fetching tip block height from: {}", url);
 
     let response = client.get(&url).send().await.map_err(|e| {
         error!("Failed to fetch tip block height: {}", e);
-        UtilsError::HttpRequestError(e.to_string())
+        UtilsError::HttpRequestError(e)
     })?;
+    check_rate_limit(&response)?;
 
     let height_text = response.text().await.map_err(|e| {
         error!("Failed to parse tip block height response: {}", e);
@@ -139,14 +575,15 @@ pub async fn fetch_tip_block_height(rpc_url: &str) -> Result<u32, UtilsError> {
 }
 #[allow(dead_code)]
 pub async fn fetch_recommended_fee_rate(base_url: &str) -> Result<RecommendedFeeRate, UtilsError> {
-    let client = Client::new();
-    let url = format!("{}/v1/fees/recommended", base_url.trim_end_matches('/'));
+    let client = http_client();
+    let url = join_url(base_url, "v1/fees/recommended");
     info!("Fetching recommended fee rate from: {}", url);
 
     let response = client.get(&url).send().await.map_err(|e| {
         error!("Failed to fetch recommended fee rate: {}", e);
-        UtilsError::HttpRequestError(e.to_string())
+        UtilsError::HttpRequestError(e)
     })?;
+    check_rate_limit(&response)?;
 
     let fee_rate = response.json::<RecommendedFeeRate>().await.map_err(|e| {
         error!("Failed to parse recommended fee rate response: {}", e);
@@ -155,4 +592,415 @@ pub async fn fetch_recommended_fee_rate(base_url: &str) -> Result<RecommendedFee
 
     info!("Fetched recommended fee rate: {:?}", fee_rate);
     Ok(fee_rate)
-}
\ No newline at end of file
+}
+
+/// Fetches the projected mempool blocks from mempool.space's `/api/v1/fees/mempool-blocks`,
+/// next block first.
+pub async fn fetch_mempool_blocks(base_url: &str) -> Result<Vec<MempoolBlock>, UtilsError> {
+    let client = http_client();
+    let url = join_url(base_url, "v1/fees/mempool-blocks");
+    info!("Fetching projected mempool blocks from: {}", url);
+
+    let response = client.get(&url).send().await.map_err(|e| {
+        error!("Failed to fetch mempool blocks: {}", e);
+        UtilsError::HttpRequestError(e)
+    })?;
+    check_rate_limit(&response)?;
+
+    let blocks = response.json::<Vec<MempoolBlock>>().await.map_err(|e| {
+        error!("Failed to parse mempool blocks response: {}", e);
+        UtilsError::ParseError(e.to_string())
+    })?;
+
+    info!("Fetched {} projected mempool blocks", blocks.len());
+    Ok(blocks)
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::error::ApiError;
+    use bitcoin::hashes::Hash;
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::str::FromStr;
+    use std::sync::{Arc, Mutex};
+
+    /// Canned HTTP response served by `spawn_mock_server`.
+    pub(crate) struct MockResponse {
+        pub status: u16,
+        pub headers: Vec<(String, String)>,
+        pub body: Vec<u8>,
+    }
+
+    impl MockResponse {
+        pub(crate) fn new(status: u16, body: &str) -> Self {
+            MockResponse {
+                status,
+                headers: Vec::new(),
+                body: body.as_bytes().to_vec(),
+            }
+        }
+
+        pub(crate) fn header(mut self, name: &str, value: &str) -> Self {
+            self.headers.push((name.to_string(), value.to_string()));
+            self
+        }
+    }
+
+    /// Serves `responses` in order, one per connection, and records each request's
+    /// head: the request line followed by its headers.
+    pub(crate) fn spawn_mock_server(
+        responses: Vec<MockResponse>,
+    ) -> (String, Arc<Mutex<Vec<String>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let base_url = format!("http://{}", listener.local_addr().unwrap());
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let recorded = requests.clone();
+
+        std::thread::spawn(move || {
+            for response in responses {
+                let (mut stream, _) = listener.accept().unwrap();
+                let mut buf = Vec::new();
+                let mut chunk = [0u8; 4096];
+                // Read headers, then the body according to Content-Length
+                loop {
+                    let n = stream.read(&mut chunk).unwrap();
+                    buf.extend_from_slice(&chunk[..n]);
+                    if let Some(end) = buf.windows(4).position(|w| w == b"\r\n\r\n") {
+                        let head = String::from_utf8_lossy(&buf[..end]).to_string();
+                        let content_length = head
+                            .lines()
+                            .find_map(|line| {
+                                let (name, value) = line.split_once(':')?;
+                                name.eq_ignore_ascii_case("content-length")
+                                    .then(|| value.trim().parse::<usize>().ok())?
+                            })
+                            .unwrap_or(0);
+                        while buf.len() < end + 4 + content_length {
+                            let n = stream.read(&mut chunk).unwrap();
+                            buf.extend_from_slice(&chunk[..n]);
+                        }
+                        recorded.lock().unwrap().push(head.clone());
+                        break;
+                    }
+                    if n == 0 {
+                        break;
+                    }
+                }
+
+                let mut head = format!(
+                    "HTTP/1.1 {} MOCK\r\nContent-Length: {}\r\nConnection: close\r\n",
+                    response.status,
+                    response.body.len()
+                );
+                for (name, value) in &response.headers {
+                    head.push_str(&format!("{}: {}\r\n", name, value));
+                }
+                head.push_str("\r\n");
+                stream.write_all(head.as_bytes()).unwrap();
+                stream.write_all(&response.body).unwrap();
+            }
+        });
+
+        (base_url, requests)
+    }
+
+    #[tokio::test]
+    async fn test_gzip_utxo_response() {
+        use flate2::Compression;
+        use flate2::write::GzEncoder;
+
+        let utxos: Vec<String> = (0..200)
+            .map(|vout| {
+                format!(
+                    r#"{{"txid":"{:064x}","vout":{},"value":1000,"status":{{"confirmed":true,"block_height":100,"block_hash":"{:064x}","block_time":1700000000}}}}"#,
+                    vout, vout, 1
+                )
+            })
+            .collect();
+        let body = format!("[{}]", utxos.join(","));
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(body.as_bytes()).unwrap();
+        let compressed = encoder.finish().unwrap();
+        assert!(compressed.len() < body.len());
+
+        let response = MockResponse {
+            body: compressed,
+            ..MockResponse::new(200, "")
+        }
+        .header("Content-Encoding", "gzip");
+        let (base_url, requests) = spawn_mock_server(vec![response]);
+        let address = Address::from_str("tb1qleejf8n05j660f74q69pwhvyg8n620xz8r60h2")
+            .unwrap()
+            .assume_checked();
+
+        let fetched = fetch_utxos_for_address(&base_url, &address).await.unwrap();
+        assert_eq!(fetched.len(), 200);
+        assert_eq!(fetched[199].vout, 199);
+        assert_eq!(fetched[0].status.block_height, 100);
+        let head = requests.lock().unwrap()[0].to_lowercase();
+        assert!(head.contains("accept-encoding: gzip"));
+    }
+
+    #[test]
+    fn test_estimated_blocks_for_rate() {
+        let estimates: BTreeMap<u16, f64> =
+            [(1, 20.5), (3, 15.0), (6, 10.2), (144, 2.0), (1008, 1.0)]
+                .into_iter()
+                .collect();
+
+        assert_eq!(estimated_blocks_for_rate(&estimates, 25), 1);
+        assert_eq!(estimated_blocks_for_rate(&estimates, 15), 3);
+        assert_eq!(estimated_blocks_for_rate(&estimates, 11), 6);
+        assert_eq!(estimated_blocks_for_rate(&estimates, 5), 144);
+        assert_eq!(estimated_blocks_for_rate(&estimates, 0), u16::MAX);
+    }
+
+    fn tx_ref_json(txid: &str, block_height: Option<u32>) -> String {
+        match block_height {
+            Some(height) => format!(
+                r#"{{"txid":"{}","status":{{"confirmed":true,"block_height":{},"block_hash":"00","block_time":1}}}}"#,
+                txid, height
+            ),
+            None => format!(r#"{{"txid":"{}","status":{{"confirmed":false}}}}"#, txid),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_fetch_address_txs_follows_pages() {
+        let address = Address::from_str("tb1qleejf8n05j660f74q69pwhvyg8n620xz8r60h2")
+            .unwrap()
+            .assume_checked();
+
+        // One mempool tx plus a full page of confirmed txs, then a short second page
+        let mut first_page = vec![tx_ref_json(&format!("{:064x}", 999), None)];
+        first_page.extend((0..25).map(|i| tx_ref_json(&format!("{:064x}", i), Some(1000 - i))));
+        let second_page: Vec<String> = (25..28)
+            .map(|i| tx_ref_json(&format!("{:064x}", i), Some(1000 - i)))
+            .collect();
+
+        let (base_url, requests) = spawn_mock_server(vec![
+            MockResponse::new(200, &format!("[{}]", first_page.join(","))),
+            MockResponse::new(200, &format!("[{}]", second_page.join(","))),
+        ]);
+
+        let txs = fetch_address_txs(&base_url, &address)
+            .await
+            .expect("Expected paged history");
+        assert_eq!(txs.len(), 29);
+        assert!(!txs[0].status.confirmed);
+        assert_eq!(txs[28].status.block_height, Some(973));
+
+        let requests = requests.lock().unwrap();
+        assert_eq!(requests.len(), 2);
+        assert!(requests[1].contains(&format!("/txs/chain/{:064x}", 24)));
+    }
+
+    #[tokio::test]
+    async fn test_rate_limited_with_retry_after() {
+        let (base_url, _) = spawn_mock_server(vec![
+            MockResponse::new(429, "Too Many Requests").header("Retry-After", "5"),
+        ]);
+
+        let result = fetch_tip_block_height(&base_url).await;
+        match result {
+            Err(UtilsError::RateLimited { retry_after }) => {
+                assert_eq!(retry_after, Duration::from_secs(5))
+            }
+            other => panic!("Expected RateLimited, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_with_retry_honors_rate_limit() {
+        let (base_url, requests) = spawn_mock_server(vec![
+            MockResponse::new(429, "Too Many Requests").header("Retry-After", "0"),
+            MockResponse::new(200, "2315994"),
+        ]);
+
+        let height = with_retry(3, || fetch_tip_block_height(&base_url))
+            .await
+            .expect("Expected height after retry");
+        assert_eq!(height, 2315994);
+        assert_eq!(requests.lock().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_utils_error_to_api_error() {
+        let err = UtilsError::ParseError("unexpected end of input".to_string());
+        assert_eq!(
+            err.to_api_error(),
+            ApiError {
+                code: "parse_error".to_string(),
+                message: "Failed to parse response: unexpected end of input".to_string(),
+            }
+        );
+
+        let err = UtilsError::BroadcastError {
+            status: reqwest::StatusCode::BAD_REQUEST,
+            message: "bad-txns-inputs-missingorspent".to_string(),
+        };
+        let api_error = err.to_api_error();
+        assert_eq!(api_error.code, "broadcast_error");
+        assert!(api_error.message.contains("bad-txns-inputs-missingorspent"));
+    }
+
+    #[tokio::test]
+    async fn test_is_outpoint_spent() {
+        let txid = Txid::all_zeros();
+        let spending_txid = "1a52ad2f0dbb56eb4a098a34b1e40c5931de8e6e59bf3c86a672269a8bd99730";
+        let (base_url, requests) = spawn_mock_server(vec![
+            MockResponse::new(
+                200,
+                &format!(
+                    r#"{{"spent":true,"txid":"{}","vin":0,"status":{{"confirmed":false}}}}"#,
+                    spending_txid
+                ),
+            ),
+            MockResponse::new(200, r#"{"spent":false}"#),
+        ]);
+
+        let spent = is_outpoint_spent(&base_url, &txid, 1).await.unwrap();
+        assert_eq!(spent, Some(Txid::from_str(spending_txid).unwrap()));
+        assert!(requests.lock().unwrap()[0].starts_with(&format!("GET /tx/{}/outspend/1 ", txid)));
+        assert_eq!(is_outpoint_spent(&base_url, &txid, 0).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn test_fetch_mempool_blocks() {
+        let (base_url, requests) = spawn_mock_server(vec![MockResponse::new(
+            200,
+            r#"[{"blockSize":1604218,"blockVSize":997962.25,"nTx":3128,"totalFees":10877512,"medianFee":9.05,"feeRange":[7.02,7.5,8.01,9.05,12.1,20.3,301.5]},{"blockSize":1748322,"blockVSize":997989,"nTx":2953,"totalFees":6135409,"medianFee":5.12,"feeRange":[4.01,4.5,5.0,5.12,6.0,7.0]},{"blockSize":5210436,"blockVSize":2930451.5,"nTx":11624,"totalFees":8014577,"medianFee":2.01,"feeRange":[1,1.5,2.01,3,4]}]"#,
+        )]);
+
+        let blocks = fetch_mempool_blocks(&base_url).await.unwrap();
+        assert!(requests.lock().unwrap()[0].starts_with("GET /v1/fees/mempool-blocks "));
+        assert_eq!(blocks.len(), 3);
+        assert_eq!(blocks[0].n_tx, 3128);
+        assert_eq!(blocks[0].block_v_size, 997962.25);
+        assert_eq!(blocks[1].fee_range.len(), 6);
+
+        assert_eq!(rate_to_enter_block(&blocks, 1), Some(10));
+        assert_eq!(rate_to_enter_block(&blocks, 3), Some(3));
+        assert_eq!(rate_to_enter_block(&blocks, 0), None);
+        assert_eq!(rate_to_enter_block(&blocks, 4), None);
+    }
+
+    #[tokio::test]
+    async fn test_partial_fee_response_falls_back() {
+        let (base_url, _) = spawn_mock_server(vec![MockResponse::new(
+            200,
+            r#"{"fastestFee":12,"halfHourFee":8,"hourFee":5,"minimumFee":1}"#,
+        )]);
+
+        let fee_rates = fetch_recommended_fee_rate(&base_url)
+            .await
+            .expect("Expected a partial response to parse");
+        assert_eq!(fee_rates.economy_fee, None);
+        assert_eq!(fee_rates.rate_for(FeeTier::Economy), Some(5));
+        assert_eq!(fee_rates.rate_for(FeeTier::Minimum), Some(1));
+        assert_eq!(fee_rates.rate_for(FeeTier::Fastest), Some(12));
+
+        let only_half_hour = RecommendedFeeRate {
+            half_hour_fee: Some(8),
+            ..Default::default()
+        };
+        assert_eq!(only_half_hour.rate_for(FeeTier::Economy), Some(8));
+        assert_eq!(only_half_hour.rate_for(FeeTier::Fastest), None);
+    }
+
+    #[test]
+    fn test_utxos_from_psbt() {
+        let script_pubkey = bitcoin::ScriptBuf::new_p2wpkh(&bitcoin::WPubkeyHash::all_zeros());
+        let txid = |byte: u8| Txid::from_byte_array([byte; 32]);
+        let input = |txid: Txid, vout: u32| bitcoin::TxIn {
+            previous_output: bitcoin::OutPoint::new(txid, vout),
+            ..Default::default()
+        };
+        let unsigned_tx = Transaction {
+            version: bitcoin::transaction::Version::TWO,
+            lock_time: bitcoin::absolute::LockTime::ZERO,
+            input: vec![input(txid(1), 0), input(txid(2), 3), input(txid(3), 0)],
+            output: vec![],
+        };
+        let mut psbt = Psbt::from_unsigned_tx(unsigned_tx).unwrap();
+        for (index, value) in [(0, 1500), (1, 2500)] {
+            psbt.inputs[index].witness_utxo = Some(bitcoin::TxOut {
+                value: bitcoin::Amount::from_sat(value),
+                script_pubkey: script_pubkey.clone(),
+            });
+        }
+
+        // Survives serialization, and the input without UTXO data is skipped
+        let psbt = Psbt::deserialize(&psbt.serialize()).unwrap();
+        let utxos = utxos_from_psbt(&psbt);
+        assert_eq!(utxos.len(), 2);
+        assert_eq!(utxos[0].txid, txid(1).to_string());
+        assert_eq!((utxos[0].vout, utxos[0].value), (0, 1500));
+        assert_eq!(utxos[1].txid, txid(2).to_string());
+        assert_eq!((utxos[1].vout, utxos[1].value), (3, 2500));
+        assert!(!utxos[0].status.confirmed);
+    }
+
+    #[test]
+    fn test_default_esplora_url() {
+        let cases = [
+            (Network::Bitcoin, "https://mempool.space/api"),
+            (Network::Testnet, "https://mempool.space/testnet/api"),
+            (Network::Testnet4, "https://mempool.space/testnet4/api"),
+            (Network::Signet, "https://mempool.space/signet/api"),
+            (Network::Regtest, "http://127.0.0.1:3002"),
+        ];
+        for (network, expected) in cases {
+            assert_eq!(default_esplora_url(network), expected);
+        }
+        assert_eq!(
+            join_url(default_esplora_url(Network::Bitcoin), "/blocks/tip/height"),
+            "https://mempool.space/api/blocks/tip/height"
+        );
+    }
+
+    #[test]
+    fn test_payment_uri() {
+        let address =
+            Address::from_str("tb1px4qe74pc66rklv2pvk59xszsfff6xnkuluhzt3te23hdgaawtuqque804v")
+                .unwrap()
+                .assume_checked();
+        assert_eq!(
+            payment_uri(&address, 50_000, "Swap 1/2"),
+            "bitcoin:tb1px4qe74pc66rklv2pvk59xszsfff6xnkuluhzt3te23hdgaawtuqque804v?amount=0.0005&label=Swap%201%2F2"
+        );
+        assert!(payment_uri(&address, 100_000_000, "swap").ends_with("?amount=1&label=swap"));
+        assert!(payment_uri(&address, 1, "").ends_with("?amount=0.00000001&label="));
+    }
+
+    #[test]
+    fn test_join_url() {
+        for base in [
+            "https://mempool.space/testnet/api",
+            "https://mempool.space/testnet/api/",
+            "https://mempool.space/testnet/api//",
+        ] {
+            assert_eq!(join_url(base, "tx"), "https://mempool.space/testnet/api/tx");
+            assert_eq!(
+                join_url(base, "/blocks/tip/height"),
+                "https://mempool.space/testnet/api/blocks/tip/height"
+            );
+        }
+        assert_eq!(join_url("http://host:3000", "tx"), "http://host:3000/tx");
+        assert_eq!(
+            join_url("http://host/esplora/api/", ""),
+            "http://host/esplora/api"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_requests_keep_base_subpath() {
+        let (base_url, requests) = spawn_mock_server(vec![MockResponse::new(200, "2315994")]);
+        let base_url = format!("{}/esplora/api/", base_url);
+
+        assert_eq!(fetch_tip_block_height(&base_url).await.unwrap(), 2315994);
+        assert!(requests.lock().unwrap()[0].starts_with("GET /esplora/api/blocks/tip/height "));
+    }
+}